@@ -1,19 +1,128 @@
 use std::{cmp, str, slice};
-use std::io::{IoError, IoResult};
+use std::io::{IoError, IoResult, standard_error, EndOfFile};
 
 pub struct LookaheadBuffer<'a> {
-    priv buf: &'a mut Buffer,
+    priv buf: Option<&'a mut Buffer>, // `None` if reading from `slice`
+    priv slice: &'a [u8], // only used when `buf` is `None`, where `savedpos` is a position in it
     priv saved: Vec<u8>,
     priv savedpos: uint,
     priv savederr: Option<IoError>,
+    priv marks: uint, // the number of active marks; everything read is kept in `saved` if > 0
+    priv consumed: uint,
+    priv limit: Option<uint>, // the buffer appears to end when `consumed` reaches this
+}
+
+/// A saved position in `LookaheadBuffer`, created by `LookaheadBuffer::mark`.
+pub struct Mark {
+    priv pos: uint,
 }
 
 impl<'a> LookaheadBuffer<'a> {
     pub fn new(buf: &'a mut Buffer) -> LookaheadBuffer<'a> {
-        LookaheadBuffer { buf: buf, saved: Vec::new(), savedpos: 0, savederr: None }
+        LookaheadBuffer { buf: Some(buf), slice: &[], saved: Vec::new(), savedpos: 0,
+                          savederr: None, marks: 0, consumed: 0, limit: None }
+    }
+
+    /// Creates a buffer reading directly from the slice, which can lend the bytes
+    /// with the lifetime of the slice. See `borrow_str`.
+    pub fn from_slice(slice: &'a [u8]) -> LookaheadBuffer<'a> {
+        LookaheadBuffer { buf: None, slice: slice, saved: Vec::new(), savedpos: 0,
+                          savederr: None, marks: 0, consumed: 0, limit: None }
+    }
+
+    /// Returns the number of bytes consumed so far, excluding any rewound bytes.
+    pub fn consumed(&self) -> uint {
+        self.consumed
+    }
+
+    /// Returns the next `len` bytes as a string borrowed from the slice, or `None`
+    /// if the buffer does not read from the slice or the bytes are not valid UTF-8.
+    pub fn borrow_str(&self, len: uint) -> Option<&'a str> {
+        if self.buf.is_some() || self.savedpos + len > self.slice.len() { return None; }
+        str::from_utf8(self.slice.slice(self.savedpos, self.savedpos + len))
+    }
+
+    /// Marks the current position. Every mark should be given back to either `rewind`
+    /// or `release`, and the buffer keeps all bytes read while there is any active mark.
+    pub fn mark(&mut self) -> Mark {
+        self.discard_consumed();
+        self.marks += 1;
+        Mark { pos: self.savedpos }
+    }
+
+    /// Rewinds the buffer to the marked position, so that the bytes consumed since then
+    /// can be read again. The mark is released.
+    pub fn rewind(&mut self, mark: Mark) {
+        assert!(self.marks > 0);
+        assert!(mark.pos <= self.savedpos);
+        self.consumed -= self.savedpos - mark.pos;
+        self.savedpos = mark.pos;
+        self.marks -= 1;
+        self.discard_consumed();
+    }
+
+    /// Returns the number of bytes consumed since the mark.
+    pub fn consumed_since(&self, mark: &Mark) -> uint {
+        self.savedpos - mark.pos
+    }
+
+    /// Releases the mark without rewinding.
+    pub fn release(&mut self, mark: Mark) {
+        assert!(self.marks > 0);
+        assert!(mark.pos <= self.savedpos);
+        self.marks -= 1;
+        self.discard_consumed();
+    }
+
+    // drops the consumed bytes from `saved` unless a mark needs them. the remaining bytes
+    // are moved to the front only when they are not more than the dropped bytes, so that
+    // the cost is amortized over the consumed bytes and `saved` stays within twice the
+    // lookahead.
+    fn discard_consumed(&mut self) {
+        if self.buf.is_none() || self.marks > 0 || self.savedpos == 0 { return; }
+        let remaining = self.saved.len() - self.savedpos;
+        if remaining > self.savedpos { return; }
+        for i in range(0, remaining) {
+            let ch = *self.saved.get(self.savedpos + i);
+            *self.saved.get_mut(i) = ch;
+        }
+        self.saved.truncate(remaining);
+        self.savedpos = 0;
+    }
+
+    /// Returns the current limit set by `set_limit`.
+    pub fn limit(&self) -> Option<uint> {
+        self.limit
+    }
+
+    /// Makes the buffer appear to end after `limit` bytes are consumed in total, as
+    /// returned by `consumed`, or removes the limit with `None`. The limit should not be
+    /// before the current position.
+    pub fn set_limit(&mut self, limit: Option<uint>) {
+        assert!(limit.map_or(true, |limit| limit >= self.consumed));
+        self.limit = limit;
     }
 
     pub fn fill_request<'a>(&'a mut self, amt: uint) -> IoResult<&'a [u8]> {
+        let limit = self.limit;
+        match limit {
+            Some(limit) => {
+                let avail = limit - self.consumed;
+                if avail == 0 { return Err(standard_error(EndOfFile)); }
+                let buf = try!(self.fill_request_unlimited(cmp::min(amt, avail)));
+                Ok(buf.slice_to(cmp::min(buf.len(), avail)))
+            }
+            None => self.fill_request_unlimited(amt),
+        }
+    }
+
+    fn fill_request_unlimited<'a>(&'a mut self, amt: uint) -> IoResult<&'a [u8]> {
+        if self.buf.is_none() {
+            // the slice is all we have, so `amt` doesn't matter
+            if self.savedpos == self.slice.len() { return Err(standard_error(EndOfFile)); }
+            return Ok(self.slice.slice_from(self.savedpos));
+        }
+
         if self.savedpos == self.saved.len() {
             // give a saved error if any
             match self.savederr.take() {
@@ -23,48 +132,45 @@ impl<'a> LookaheadBuffer<'a> {
 
             // try *not* to use the `saved` buffer if possible
             // we have no buffers to return in front of it, so we can directly give the error
-            let consume;
-            {
-                let buf = try!(self.buf.fill());
-                if buf.len() >= amt {
-                    consume = None;
-                } else {
-                    self.saved.clear();
-                    self.saved.push_all(buf);
-                    self.savedpos = 0;
-                    consume = Some(buf.len());
-                }
-            };
-            match consume {
-                None => {
-                    // we can't borrow `buf` this longer...
-                    return Ok(try!(self.buf.fill()));
-                }
-                Some(buflen) => {
-                    self.buf.consume(buflen);
+            // (but we have to keep every byte read if there is any mark)
+            if self.marks == 0 {
+                let consume;
+                {
+                    let buf = try!(self.buf.get_mut_ref().fill());
+                    if buf.len() >= amt {
+                        consume = None;
+                    } else {
+                        self.saved.clear();
+                        self.saved.push_all(buf);
+                        self.savedpos = 0;
+                        consume = Some(buf.len());
+                    }
+                };
+                match consume {
+                    None => {
+                        // we can't borrow `buf` this longer...
+                        return Ok(try!(self.buf.get_mut_ref().fill()));
+                    }
+                    Some(buflen) => {
+                        self.buf.get_mut_ref().consume(buflen);
+                    }
                 }
             }
-        } else if self.savedpos > 0 {
-            // TODO amortize this: we need to occasionally shrink the `saved` buffer,
-            // otherwise we may hit the pathological case when the caller repeatedly
-            // request the large amount of buffers, but we can't always do this
-            // since it will significantly degrade the typical performance.
-            //
-            //for i in range(self.savedpos, self.saved.len()) {
-            //    self.saved[i] = self.saved[i - self.savedpos];
-            //}
-            //self.savedpos = 0;
+        } else {
+            // otherwise the caller repeatedly requesting the large amount of buffers
+            // would grow the `saved` buffer without a bound
+            self.discard_consumed();
         }
 
         // only call `fill` when the `saved` buffer is not enough
         // if there is a saved error, we don't bother reading though
-        let minlen = self.savedpos + amt;
+        // (`fill_request(0)` should read something if the `saved` buffer is exhausted)
+        let minlen = self.savedpos + cmp::max(amt, 1);
         if self.saved.len() < minlen && self.savederr.is_none() {
             loop {
-                let consume = match self.buf.fill() {
+                let consume = match self.buf.get_mut_ref().fill() {
                     Ok(buf) => {
                         self.saved.push_all(buf);
-                        if self.saved.len() >= minlen { break; }
                         buf.len()
                     }
                     Err(err) => {
@@ -72,7 +178,8 @@ impl<'a> LookaheadBuffer<'a> {
                         break;
                     }
                 };
-                self.buf.consume(consume);
+                self.buf.get_mut_ref().consume(consume);
+                if self.saved.len() >= minlen { break; }
             }
         }
 
@@ -156,20 +263,27 @@ impl<'a> LookaheadBuffer<'a> {
     }
 
     pub fn peek_char(&mut self) -> IoResult<Option<char>> {
+        Ok(try!(self.peek_char_at(0)).map(|(ch, _)| ch))
+    }
+
+    /// Peeks a character starting at `offset` bytes ahead of the current position,
+    /// returning the character and its length in bytes. Returns `None` at the end of
+    /// the stream or at an invalid UTF-8 sequence.
+    pub fn peek_char_at(&mut self, offset: uint) -> IoResult<Option<(char, uint)>> {
         let width;
         {
-            let buf = try!(self.fill_request(1));
-            if buf.is_empty() { return Ok(None); }
-            let first_byte = buf[0];
+            let buf = try!(self.fill_request(offset + 1));
+            if buf.len() <= offset { return Ok(None); }
+            let first_byte = buf[offset];
             width = str::utf8_char_width(first_byte);
-            if width == 1 { return Ok(Some(first_byte as char)); }
+            if width == 1 { return Ok(Some((first_byte as char, 1))); }
             if width == 0 { return Ok(None); }
         }
 
-        let buf = try!(self.fill_request(width));
-        if buf.len() < width { return Ok(None); }
-        match str::from_utf8(buf.slice_to(width)) {
-            Some(s) => Ok(Some(s.char_at(0))),
+        let buf = try!(self.fill_request(offset + width));
+        if buf.len() < offset + width { return Ok(None); }
+        match str::from_utf8(buf.slice(offset, offset + width)) {
+            Some(s) => Ok(Some((s.char_at(0), width))),
             None => Ok(None),
         }
     }
@@ -196,8 +310,12 @@ impl<'a> Buffer for LookaheadBuffer<'a> {
     }
 
     fn consume(&mut self, amt: uint) {
-        if self.savedpos == self.saved.len() {
-            self.buf.consume(amt);
+        self.consumed += amt;
+        if self.buf.is_none() {
+            self.savedpos += amt;
+            assert!(self.savedpos <= self.slice.len());
+        } else if self.savedpos == self.saved.len() && self.marks == 0 {
+            self.buf.get_mut_ref().consume(amt);
         } else {
             self.savedpos += amt;
             assert!(self.savedpos <= self.saved.len());
@@ -206,20 +324,20 @@ impl<'a> Buffer for LookaheadBuffer<'a> {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
     use std::{cmp, slice};
     use std::io::{standard_error, IoResult, EndOfFile};
 
-    // used to simulate the corner cases
-    struct SimulatedBuffer<'a> {
-        calls: &'a [&'a [u8]],
-        index: uint,
-        pos: uint,
+    // used to simulate the corner cases, also in `rt` tests
+    pub struct SimulatedBuffer<'a> {
+        priv calls: &'a [&'a [u8]],
+        priv index: uint,
+        priv pos: uint,
     }
 
     impl<'a> SimulatedBuffer<'a> {
-        fn new<'a>(calls: &'a [&'a [u8]]) -> SimulatedBuffer<'a> {
+        pub fn new<'a>(calls: &'a [&'a [u8]]) -> SimulatedBuffer<'a> {
             SimulatedBuffer { calls: calls, index: 0, pos: 0 }
         }
     }
@@ -364,6 +482,137 @@ mod tests {
         assert_eq!(lab.fill_request(0).unwrap(), &[3,4,5,6,7]);
         lab.consume(1);
         assert_eq!(lab.fill_request(0).unwrap(), &[4,5,6,7]);
+        lab.consume(4);
+        assert!(lab.fill_request(0).is_err()); // should not see `5,6,7` again
     }
-}
 
+    #[test]
+    fn test_peek_char_at() {
+        // "aé가" split in the middle of multibyte sequences
+        let buf = &[&[0x61, 0xc3], &[0xa9, 0xea], &[0xb0], &[0x80]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut lab = LookaheadBuffer::new(&mut b);
+        assert_eq!(lab.peek_char_at(0).unwrap(), Some(('a', 1)));
+        assert_eq!(lab.peek_char_at(1).unwrap(), Some(('é', 2)));
+        assert_eq!(lab.peek_char_at(3).unwrap(), Some(('가', 3)));
+        assert_eq!(lab.peek_char_at(6).unwrap(), None);
+        assert_eq!(lab.peek_char().unwrap(), Some('a'));
+        lab.consume(3);
+        assert_eq!(lab.peek_char().unwrap(), Some('가'));
+
+        let buf = &[&[0x61, 0xc3]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut lab = LookaheadBuffer::new(&mut b);
+        assert_eq!(lab.peek_char_at(1).unwrap(), None); // incomplete sequence
+    }
+    #[test]
+    fn test_mark_and_rewind() {
+        let buf = &[&[1,2,3], &[4], &[5,6,7]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut lab = LookaheadBuffer::new(&mut b);
+        assert_eq!(lab.fill_request(0).unwrap(), &[1,2,3]);
+        lab.consume(1);
+        let mark = lab.mark();
+        assert_eq!(lab.fill_request(0).unwrap(), &[2,3]);
+        lab.consume(2);
+        assert_eq!(lab.fill_request(0).unwrap(), &[4]);
+        lab.consume(1);
+        assert_eq!(lab.fill_request(2).unwrap(), &[5,6,7]);
+        lab.consume(1);
+        assert_eq!(lab.consumed_since(&mark), 4);
+        lab.rewind(mark);
+        assert_eq!(lab.fill_request(0).unwrap(), &[2,3,4,5,6,7]);
+
+        // nested marks
+        lab.consume(2);
+        let outer = lab.mark();
+        lab.consume(1);
+        let inner = lab.mark();
+        assert_eq!(lab.fill_request(0).unwrap(), &[5,6,7]);
+        lab.consume(3);
+        lab.rewind(inner);
+        assert_eq!(lab.fill_request(0).unwrap(), &[5,6,7]);
+        lab.release(outer);
+        lab.consume(3);
+        assert!(lab.fill_request(0).is_err());
+        assert_eq!(lab.consumed(), 7);
+    }
+
+    #[test]
+    fn test_rewind_at_eof() {
+        let buf = &[&[1,2]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut lab = LookaheadBuffer::new(&mut b);
+        let mark = lab.mark();
+        assert_eq!(lab.fill_request(5).unwrap(), &[1,2]);
+        lab.consume(2);
+        assert!(lab.fill_request(1).is_err());
+        lab.rewind(mark);
+        assert_eq!(lab.fill_request(1).unwrap(), &[1,2]);
+        lab.consume(2);
+        assert!(lab.fill_request(1).is_err());
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let s = "가나다 라";
+        let mut lab = LookaheadBuffer::from_slice(s.as_bytes());
+        assert_eq!(lab.fill_request(100).unwrap(), s.as_bytes());
+        assert_eq!(lab.peek_char_at(3).unwrap(), Some(('나', 3)));
+        let mark = lab.mark();
+        lab.consume(3);
+        assert_eq!(lab.borrow_str(6), Some("나다"));
+        assert_eq!(lab.borrow_str(1), None); // not a character boundary
+        assert_eq!(lab.borrow_str(100), None);
+        lab.rewind(mark);
+        assert_eq!(lab.consumed(), 0);
+        assert_eq!(lab.read_pad_char('가').unwrap(), 1);
+        assert_eq!(lab.consumed(), 3);
+        lab.consume(s.len() - 3);
+        assert!(lab.fill_request(0).is_err());
+    }
+
+    #[test]
+    fn test_limit() {
+        let buf = &[&[1,2,3], &[4,5,6]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut lab = LookaheadBuffer::new(&mut b);
+        lab.set_limit(Some(4));
+        assert_eq!(lab.fill_request(100).unwrap(), &[1,2,3,4]);
+        assert_eq!(lab.read_pad_byte_if(|_| true).unwrap_err().kind, EndOfFile);
+        assert_eq!(lab.consumed(), 4);
+        assert!(lab.fill_request(0).is_err());
+        lab.set_limit(None);
+        assert_eq!(lab.fill_request(2).unwrap(), &[5,6]);
+    }
+
+    #[test]
+    fn test_saved_bounded() {
+        // `Records` marks every record, and the records rarely end at the chunk boundary
+        let mut data = Vec::new();
+        for _ in range(0, 3000) { data.push_all(bytes!("ab;")); }
+        let chunks: Vec<&[u8]> = data.as_slice().chunks(4).collect();
+        let mut b = SimulatedBuffer::new(chunks.as_slice());
+        let mut lab = LookaheadBuffer::new(&mut b);
+        let mut nrecords = 0;
+        loop {
+            if lab.peek_byte().is_err() { break; }
+            let mark = lab.mark();
+            assert_eq!(lab.read_pad_byte_if(|ch| ch != ';' as u8).unwrap(), 2);
+            lab.consume(1);
+            if nrecords % 2 == 0 {
+                lab.release(mark);
+            } else {
+                // the rewound record is read again
+                lab.rewind(mark);
+                let mark = lab.mark();
+                lab.consume(3);
+                lab.release(mark);
+            }
+            nrecords += 1;
+            assert!(lab.saved.len() <= 16);
+        }
+        assert_eq!(nrecords, 3000);
+        assert_eq!(lab.consumed(), 9000);
+    }
+}
@@ -0,0 +1,359 @@
+use collections::{HashMap, HashSet};
+
+use syntax::ast;
+use syntax::ast::{MetaItem, Item, Expr, Ty};
+use syntax::attr::AttrMetaMethods;
+use syntax::codemap::{Span, Spanned};
+use syntax::ext::base::ExtCtxt;
+use syntax::ext::build::AstBuilder;
+use syntax::parse::token;
+
+use parse::{parse_fmt, Piece, String, Whitespace, RequiredWhitespace, Argument, Group};
+use parse::{ArgumentNamed, ArgumentNext, ArgumentIs, ArgumentSuppress};
+use macros::{Args, ArgType, Lex, FmtSource, trans_pieces, trans_patterns, positional_key};
+use macros::fmt_span;
+
+/// The fields of a struct or an enum variant.
+enum Fields {
+    NamedFields(Vec<(ast::Ident, Span, @Ty)>), // `S { a: A, b: B }`, referenced as `{a}`
+    UnnamedFields(Vec<(Span, @Ty)>),           // `S(A, B)` or `S`, referenced as `{}` or `{0}`
+}
+
+/// A constructor to be scanned with its own format.
+struct Ctor {
+    ident: ast::Ident,
+    fields: Fields,
+    fmt: token::InternedString,
+    style: ast::StrStyle,
+    fmtsp: Span,
+}
+
+fn struct_fields(def: &ast::StructDef) -> Fields {
+    let is_named = def.fields.iter().any(|field| match field.node.kind {
+        ast::NamedField(..) => true,
+        _ => false,
+    });
+    if is_named {
+        NamedFields(def.fields.iter().filter_map(|field| match field.node.kind {
+            ast::NamedField(ident, _) => Some((ident, field.span, field.node.ty)),
+            _ => None,
+        }).collect())
+    } else {
+        UnnamedFields(def.fields.iter().map(|field| (field.span, field.node.ty)).collect())
+    }
+}
+
+/// Returns the format string in `#[read(fmt = "...")]` with its style and span.
+fn get_fmt(cx: &mut ExtCtxt, mitem: &MetaItem)
+          -> Option<(token::InternedString, ast::StrStyle, Span)> {
+    let mut fmt = None;
+    match mitem.node {
+        ast::MetaList(_, ref items) => {
+            for item in items.iter() {
+                match item.node {
+                    ast::MetaNameValue(ref name, ref lit) if name.get() == "fmt" => {
+                        match lit.node {
+                            ast::LitStr(ref s, style) if fmt.is_none() => {
+                                fmt = Some((s.clone(), style, lit.span));
+                            }
+                            ast::LitStr(..) => {
+                                cx.span_err(item.span, "duplicate argument `fmt`");
+                                return None;
+                            }
+                            _ => {
+                                cx.span_err(lit.span,
+                                            "format argument must be a string literal.");
+                                return None;
+                            }
+                        }
+                    }
+                    _ => {
+                        cx.span_err(item.span, "unrecognized argument to `#[read]`");
+                        return None;
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    if fmt.is_none() {
+        cx.span_err(mitem.span, "expected `#[read(fmt = \"...\")]`");
+    }
+    fmt
+}
+
+/// Returns `T` from the field type `Option<T>`.
+fn option_inner_ty(ty: &Ty) -> Option<@Ty> {
+    match ty.node {
+        ast::TyPath(ref path, None, _) => {
+            let seg = path.segments.last().unwrap();
+            if token::get_ident(seg.identifier).get() == "Option" && seg.types.len() == 1 {
+                Some(*seg.types.get(0))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Checks if every argument in the pieces refers to an existing field, and collects
+/// the keys of the arguments in optional groups. Returns false if any error has been reported.
+fn check_pieces(cx: &mut ExtCtxt, ctor: &Ctor, pieces: &[Piece],
+                fields: &HashMap<~str,(ast::Ident,Span,@Ty)>, in_group: bool,
+                group_keys: &mut HashSet<~str>) -> bool {
+    let mut ok = true;
+    for piece in pieces.iter() {
+        match *piece {
+            Argument(ref arg) => {
+                let (key, name) = match arg.position {
+                    ArgumentNamed(name) => (name.to_owned(), name.to_owned()),
+                    ArgumentIs(i) => (positional_key(i), i.to_str()),
+                    ArgumentNext => unreachable!(), // replaced by `parse_fmt`
+                    ArgumentSuppress => { continue; }
+                };
+                if !fields.contains_key(&key) {
+                    cx.span_err(ctor.fmtsp,
+                                format!("no field `{}` in `{}`",
+                                        name, token::get_ident(ctor.ident).get()));
+                    ok = false;
+                } else if in_group {
+                    group_keys.insert(key);
+                }
+            }
+            Group(ref group) => {
+                if !check_pieces(cx, ctor, group.as_slice(), fields, true, group_keys) {
+                    ok = false;
+                }
+            }
+            String(..) | Whitespace | RequiredWhitespace => {}
+        }
+    }
+    ok
+}
+
+/// Translates the format of the constructor into an expression scanning from `__lex_s`
+/// and returning the constructed value in `ScanResult`. The fields referenced in optional
+/// groups should have a type `Option<T>`. The patterns are added to `pats`.
+/// Returns `None` if any error has been reported.
+fn trans_ctor(cx: &mut ExtCtxt, sp: Span, ctor: &Ctor, pats: &mut Vec<@Expr>) -> Option<@Expr> {
+    let pieces = match parse_fmt(ctor.fmt.get()) {
+        Ok(pieces) => pieces,
+        Err(errs) => {
+            for err in errs.iter() {
+                let errsp = fmt_span(cx, ctor.fmtsp, ctor.fmt.get(), ctor.style,
+                                     err.start, err.end);
+                cx.span_err(errsp, err.msg.as_slice());
+            }
+            return None;
+        }
+    };
+
+    // the named fields are declared as variables of the same name, and the unnamed fields
+    // as positional arguments
+    let mut fields = HashMap::new();
+    let mut order = Vec::new();
+    match ctor.fields {
+        NamedFields(ref named) => {
+            for &(ident, span, ty) in named.iter() {
+                let key = token::get_ident(ident).get().to_owned();
+                fields.insert(key.clone(), (ident, span, ty));
+                order.push((key, token::get_ident(ident).get().to_owned()));
+            }
+        }
+        UnnamedFields(ref unnamed) => {
+            for (i, &(span, ty)) in unnamed.iter().enumerate() {
+                fields.insert(positional_key(i), (cx.ident_of(positional_key(i)), span, ty));
+                order.push((positional_key(i), i.to_str()));
+            }
+        }
+    }
+
+    let mut group_keys = HashSet::new();
+    if !check_pieces(cx, ctor, pieces.as_slice(), &fields, false, &mut group_keys) {
+        return None;
+    }
+
+    let mut named = HashMap::new();
+    let mut named_order = Vec::new();
+    let mut failed = false;
+    for &(ref key, ref name) in order.iter() {
+        let &(ident, span, ty) = fields.get(key);
+        let ty = if group_keys.contains(key) {
+            match option_inner_ty(ty) {
+                Some(ty) => ty,
+                None => {
+                    cx.span_err(span, format!("field `{}` in an optional group should have \
+                                               a type `Option<T>`", name));
+                    cx.parse_sess.span_diagnostic.span_note(ctor.fmtsp, "format is here");
+                    failed = true;
+                    continue;
+                }
+            }
+        } else {
+            ty
+        };
+        let spanned = Spanned { node: ident, span: span };
+        named.insert(key.clone(), (spanned, ArgType(ty)));
+        named_order.push(spanned);
+    }
+    if failed { return None; }
+
+    let fmtstr = cx.expr_str(ctor.fmtsp, ctor.fmt.clone());
+    // the derived impl scans from the caller's scanner, which may borrow from the string
+    let args = Args { extra: fmtstr, scanner: true,
+                      fmtstr: fmtstr, alternatives: vec!(fmtstr),
+                      ignore_case: false, line_mode: false, verbose: false, strict: false,
+                      columns: false, into_places: false, kind: Lex,
+                      named: named, named_order: named_order,
+                      positional: Vec::new(), npositional: 0 };
+
+    let mut used = HashSet::new();
+    let mut stmts = Vec::new();
+    let src = FmtSource { span: ctor.fmtsp, fmt: ctor.fmt.get(), style: ctor.style };
+    if !trans_pieces(cx, sp, &args, src, pieces.as_slice(), false,
+                     &mut used, pats, &mut stmts) {
+        return None;
+    }
+    for &(ref key, ref name) in order.iter() {
+        if !used.contains(key) {
+            let &(_, span, _) = fields.get(key);
+            cx.span_err(span, format!("field `{}` is never used in the format", name));
+            cx.parse_sess.span_diagnostic.span_note(ctor.fmtsp, "format is here");
+            failed = true;
+        }
+    }
+    if failed { return None; }
+
+    let value = match ctor.fields {
+        NamedFields(ref named) => {
+            let fields = named.iter().map(|&(ident, span, _)| {
+                cx.field_imm(span, ident, cx.expr_ident(span, ident))
+            }).collect();
+            cx.expr_struct_ident(sp, ctor.ident, fields)
+        }
+        UnnamedFields(ref unnamed) if unnamed.is_empty() => cx.expr_ident(sp, ctor.ident),
+        UnnamedFields(ref unnamed) => {
+            let values = range(0, unnamed.len()).map(|i| {
+                cx.expr_ident(sp, cx.ident_of(positional_key(i)))
+            }).collect();
+            cx.expr_call_ident(sp, ctor.ident, values)
+        }
+    };
+    Some(cx.expr_block(cx.block(sp, stmts, Some(quote_expr!(cx, Ok($value))))))
+}
+
+/// Expands `#[read(fmt = "...")]` on a struct, or `#[read]` on an enum with
+/// `#[read(fmt = "...")]` on every variant. Implements `rt::Read` by scanning the format
+/// with the placeholders mapped to the fields (`{name}` for named fields and `{}` or `{0}`
+/// for unnamed fields), trying the variants in order, and `FromStr` by scanning
+/// the whole string.
+pub fn expand_read(cx: &mut ExtCtxt, sp: Span, mitem: @MetaItem, item: @Item,
+                   push: |@Item|) {
+    let mut ctors = Vec::new();
+    match item.node {
+        ast::ItemStruct(def, ref generics) if !generics.is_parameterized() => {
+            let (fmt, style, fmtsp) = match get_fmt(cx, &*mitem) {
+                Some(fmt) => fmt,
+                None => { return; }
+            };
+            ctors.push(Ctor { ident: item.ident, fields: struct_fields(&*def),
+                              fmt: fmt, style: style, fmtsp: fmtsp });
+        }
+        ast::ItemEnum(ref def, ref generics) if !generics.is_parameterized() => {
+            match mitem.node {
+                ast::MetaWord(..) => {}
+                _ => {
+                    cx.span_err(mitem.span, "`#[read]` on an enum takes no arguments");
+                    cx.parse_sess.span_diagnostic.span_note(
+                        mitem.span, "put `#[read(fmt = \"...\")]` on each variant instead");
+                    return;
+                }
+            }
+            let mut failed = false;
+            for variant in def.variants.iter() {
+                let attr = variant.node.attrs.iter().find(|attr| attr.name().get() == "read");
+                let (fmt, style, fmtsp) = match attr {
+                    Some(attr) => match get_fmt(cx, &*attr.node.value) {
+                        Some(fmt) => fmt,
+                        None => { failed = true; continue; }
+                    },
+                    None => {
+                        cx.span_err(variant.span,
+                                    format!("no format for variant `{}`",
+                                            token::get_ident(variant.node.name).get()));
+                        cx.parse_sess.span_diagnostic.span_note(
+                            variant.span, "expected `#[read(fmt = \"...\")]`");
+                        failed = true;
+                        continue;
+                    }
+                };
+                let fields = match variant.node.kind {
+                    ast::TupleVariantKind(ref args) => {
+                        UnnamedFields(args.iter().map(|arg| (arg.ty.span, arg.ty)).collect())
+                    }
+                    ast::StructVariantKind(def) => struct_fields(&*def),
+                };
+                ctors.push(Ctor { ident: variant.node.name, fields: fields,
+                                  fmt: fmt, style: style, fmtsp: fmtsp });
+            }
+            if failed { return; }
+        }
+        ast::ItemStruct(..) | ast::ItemEnum(..) => {
+            cx.span_err(sp, "`#[read]` cannot be used with generic types");
+            return;
+        }
+        _ => {
+            cx.span_err(sp, "`#[read]` can only be used with structs and enums");
+            return;
+        }
+    }
+
+    // every constructor is tried in order, as the alternative formats of `lex!` are
+    let mut pats = Vec::new();
+    let mut stmts = Vec::new();
+    let mut failed = false;
+    for ctor in ctors.iter() {
+        let body = match trans_ctor(cx, sp, ctor, &mut pats) {
+            Some(body) => body,
+            None => { failed = true; continue; }
+        };
+        stmts.push(quote_stmt!(cx, {
+            let __lex_mark = __lex_s.mark();
+            let __lex_result = (|__lex_s: &mut ::read::rt::Scanner| $body)(__lex_s);
+            match __lex_result {
+                Ok(value) => {
+                    __lex_s.release(__lex_mark);
+                    return Ok(Some(value));
+                }
+                Err(err) => {
+                    __lex_s.rewind(__lex_mark);
+                    if !::read::rt::is_mismatch(&err) { return Err(err); }
+                }
+            }
+        }));
+    }
+    if failed { return; }
+    if !pats.is_empty() {
+        let pats = trans_patterns(cx, sp, pats.as_slice());
+        stmts.unshift(quote_stmt!(cx, let __lex_pats = $pats;));
+    }
+
+    let ty = cx.ty_ident(sp, item.ident);
+    let body = cx.expr_block(cx.block(sp, stmts, Some(quote_expr!(cx, Ok(None)))));
+    push(quote_item!(cx,
+        impl<'a> ::read::rt::Read<'a> for $ty {
+            fn scan(__lex_s: &mut ::read::rt::Scanner<'a>)
+                   -> ::read::rt::ScanResult<Option<$ty>> {
+                $body
+            }
+        }
+    ).unwrap());
+    push(quote_item!(cx,
+        impl ::std::from_str::FromStr for $ty {
+            fn from_str(s: &str) -> Option<$ty> {
+                ::read::rt::read_from_str(s)
+            }
+        }
+    ).unwrap());
+}
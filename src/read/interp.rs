@@ -0,0 +1,370 @@
+use collections::HashMap;
+
+use parse::{unescape, Piece, String, Whitespace, RequiredWhitespace, Argument, Group, ScanSpec};
+use parse::{ArgumentNamed, ArgumentNext, ArgumentIs, ArgumentSuppress, FlagSignPlus};
+use rt::{Scanner, Scan, Pattern, ScanError, ScanResult, InvalidFormat, InvalidNumber};
+use rt::is_mismatch;
+
+/// A value scanned by `interpret`. The variant is chosen by the scan type.
+#[deriving(Eq,Clone,Show)]
+pub enum Value {
+    Int(i64),         // `i`, `d`, and `x` and `X` with a sign
+    Uint(u64),        // `u`, `o`, `b`, and `x` and `X` without a sign
+    Float(f64),       // `f`, `e` and `E`
+    Char(char),       // `c`
+    Str(~str),        // `s`, no scan type, character classes and regular expressions
+    Bytes(Vec<u8>),   // `y`, which need not be valid UTF-8
+    List(Vec<Value>), // any repeated argument
+}
+
+/// Options changing how the pieces are matched, which `lex!` takes as the modifiers.
+#[deriving(Eq,Clone,Show)]
+pub struct InterpretOptions {
+    /// The `i` modifier. The literals and the separators of the repetitions are matched
+    /// ignoring the case, as `Scanner::expect_str_ignore_case` does.
+    ignore_case: bool,
+}
+
+/// Scans the pieces from `parse_fmt` at runtime, as the `lex!` expansion would do for
+/// the same format. Returns the values keyed by the name, or by the position in decimal
+/// for `{}` and `{0}`; the values in the optional groups not matched are missing.
+/// Fails as `lex!` does, or with `InvalidFormat` if the scan type is unknown.
+pub fn interpret<'a>(s: &mut Scanner<'a>,
+                     pieces: &[Piece]) -> ScanResult<HashMap<~str,Value>> {
+    interpret_with(s, pieces, &InterpretOptions { ignore_case: false })
+}
+
+/// Same as `interpret` but with the options.
+pub fn interpret_with<'a>(s: &mut Scanner<'a>, pieces: &[Piece],
+                          opts: &InterpretOptions) -> ScanResult<HashMap<~str,Value>> {
+    let mut values = HashMap::new();
+    try!(interpret_into_with(s, pieces, opts, &mut values));
+    Ok(values)
+}
+
+/// Same as `interpret` but inserts the values to `values`, which keeps the values
+/// scanned before the failure. `ScanError::assigned` lists their names in order.
+pub fn interpret_into<'a>(s: &mut Scanner<'a>, pieces: &[Piece],
+                          values: &mut HashMap<~str,Value>) -> ScanResult<()> {
+    interpret_into_with(s, pieces, &InterpretOptions { ignore_case: false }, values)
+}
+
+/// Same as `interpret_into` but with the options.
+pub fn interpret_into_with<'a>(s: &mut Scanner<'a>, pieces: &[Piece], opts: &InterpretOptions,
+                               values: &mut HashMap<~str,Value>) -> ScanResult<()> {
+    let mut assigned = Vec::new();
+    match interpret_pieces(s, pieces, true, opts, values, &mut assigned) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(ScanError { assigned: assigned, ..err }),
+    }
+}
+
+fn interpret_pieces<'a>(s: &mut Scanner<'a>, pieces: &[Piece], toplevel: bool,
+                        opts: &InterpretOptions, values: &mut HashMap<~str,Value>,
+                        assigned: &mut Vec<~str>) -> ScanResult<()> {
+    for (i, piece) in pieces.iter().enumerate() {
+        if toplevel { s.set_piece(i); }
+        match *piece {
+            String(lit) if opts.ignore_case => { try!(s.expect_str_ignore_case(lit)); }
+            String(lit) => { try!(s.expect_str(lit)); }
+            Whitespace => { try!(s.skip_whitespace()); }
+            RequiredWhitespace => { try!(s.expect_whitespace()); }
+            Argument(ref arg) => {
+                let name = match arg.position {
+                    ArgumentNamed(name) => Some(name.to_owned()),
+                    ArgumentIs(i) => Some(i.to_str()),
+                    ArgumentNext => unreachable!(), // replaced by `parse_fmt`
+                    ArgumentSuppress => None,
+                };
+                // the scanner only knows the static names from `lex!`
+                let value = match scan_arg(s, &arg.scan, opts) {
+                    Ok(value) => value,
+                    Err(err) => { return Err(ScanError { name: name, ..err }); }
+                };
+                match name {
+                    Some(name) => {
+                        assigned.push(name.clone());
+                        values.insert(name, value);
+                    }
+                    None => {}
+                }
+            }
+            Group(ref group) => {
+                // the values are kept aside until the whole group matches
+                let mark = s.mark();
+                let mut groupvalues = HashMap::new();
+                let nassigned = assigned.len();
+                match interpret_pieces(s, group.as_slice(), false, opts,
+                                       &mut groupvalues, assigned) {
+                    Ok(()) => {
+                        s.release(mark);
+                        for (name, value) in groupvalues.move_iter() {
+                            values.insert(name, value);
+                        }
+                    }
+                    Err(err) => {
+                        s.rewind(mark);
+                        assigned.truncate(nassigned);
+                        if !is_mismatch(&err) { return Err(err); }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scan_arg<'a>(s: &mut Scanner<'a>, spec: &ScanSpec,
+                opts: &InterpretOptions) -> ScanResult<Value> {
+    s.set_spec(None, spec.flags, spec.fill, spec.align, spec.width);
+    match spec.repeat {
+        Some(ref repeat) => {
+            let sep = repeat.sep.map(|sep| unescape(sep));
+            let values = try!(s.scan_repeat(repeat.min, repeat.max,
+                                            sep.as_ref().map(|sep| sep.as_slice()),
+                                            opts.ignore_case, |s| scan_one(s, spec)));
+            Ok(List(values))
+        }
+        None => scan_one(s, spec),
+    }
+}
+
+fn scan_one<'a>(s: &mut Scanner<'a>, spec: &ScanSpec) -> ScanResult<Value> {
+    match (&spec.class, &spec.regex) {
+        (&Some(ref class), _) => {
+            return Ok(Str(try!(Scan::for_capture(s, class as &Pattern))));
+        }
+        (_, &Some(ref regex)) => {
+            return Ok(Str(try!(Scan::for_capture(s, regex as &Pattern))));
+        }
+        (_, _) => {}
+    }
+
+    match spec.ty {
+        ""        => Ok(Str(try!(Scan::for_read(s)))),
+        "i"       => Ok(Int(try!(Scan::for_integer(s)))),
+        "d"       => Ok(Int(try!(Scan::for_signed(s)))),
+        "u"       => Ok(Uint(try!(Scan::for_unsigned(s)))),
+        "c"       => Ok(Char(try!(Scan::for_char(s)))),
+        "o"       => Ok(Uint(try!(Scan::for_octal(s)))),
+        "x" | "X" => scan_hex(s, spec),
+        "s"       => Ok(Str(try!(Scan::for_string(s)))),
+        "b"       => Ok(Uint(try!(Scan::for_binary(s)))),
+        "f"       => Ok(Float(try!(Scan::for_float(s)))),
+        "e" | "E" => Ok(Float(try!(Scan::for_exp(s)))),
+        "y"       => Ok(Bytes(try!(Scan::for_bytes(s)))),
+        _         => Err(s.error(InvalidFormat)),
+    }
+}
+
+// the hex digits are unsigned unless they have a sign, which `+` makes mandatory
+fn scan_hex<'a>(s: &mut Scanner<'a>, spec: &ScanSpec) -> ScanResult<Value> {
+    if ((spec.flags >> FlagSignPlus as uint) & 1) == 1 {
+        return Ok(Int(try!(Scan::for_hex(s))));
+    }
+    let mark = s.mark();
+    match Scan::for_hex(s) {
+        Ok(v) => { s.release(mark); Ok(Uint(v)) }
+        Err(ref err) if err.kind == InvalidNumber => {
+            s.rewind(mark);
+            Ok(Int(try!(Scan::for_hex(s))))
+        }
+        Err(err) => { s.release(mark); Err(err) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collections::HashMap;
+    use parse::{parse_fmt, parse_fmt_with, ParseOptions};
+    use rt::{Scanner, InvalidNumber, Overflow, UnexpectedEof, LiteralMismatch, InvalidFormat};
+    use rt::TrailingInput;
+
+    #[test]
+    fn test_interpret() {
+        let pieces = parse_fmt("{name} is {age:u} years old[, {w:f}kg] \
+                                {tags:*[a-z] sep \",\"}").unwrap();
+        let mut s = Scanner::from_str("Bob is 42 years old a,b");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values.get(&~"name"), &Str(~"Bob"));
+        assert_eq!(values.get(&~"age"), &Uint(42));
+        assert_eq!(values.get(&~"tags"), &List(vec!(Str(~"a"), Str(~"b"))));
+
+        let mut s = Scanner::from_str("Bob is 42 years old, 61.5kg x");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"w"), &Float(61.5));
+
+        let pieces = parse_fmt("{:c}{*:x} {:d} {2:[a-z]}").unwrap();
+        let mut s = Scanner::from_str("!ff -3 abc");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values.get(&~"0"), &Char('!'));
+        assert_eq!(values.get(&~"1"), &Int(-3));
+        assert_eq!(values.get(&~"2"), &Str(~"abc"));
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let pieces = parse_fmt("{a:d} {b:d}").unwrap();
+        let mut s = Scanner::from_str("1 x");
+        let err = interpret(&mut s, pieces.as_slice()).unwrap_err();
+        assert_eq!(err.kind, InvalidNumber);
+        assert_eq!(err.name, Some(~"b"));
+        assert_eq!(err.offset, 2);
+        assert_eq!(err.snippet, ~"x");
+
+        let mut s = Scanner::from_str("1 99999999999999999999");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("1 ");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, UnexpectedEof);
+
+        let pieces = parse_fmt("a{x}").unwrap();
+        let mut s = Scanner::from_str("b");
+        let err = interpret(&mut s, pieces.as_slice()).unwrap_err();
+        assert_eq!(err.kind, LiteralMismatch);
+        assert_eq!(err.name, None);
+
+        let pieces = parse_fmt("{a:q}").unwrap();
+        let mut s = Scanner::from_str("1");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, InvalidFormat);
+    }
+
+    #[test]
+    fn test_width() {
+        let pieces = parse_fmt("{a:4d}{b:2d}{c:3f}{d:2x} {e:3s}{f}").unwrap();
+        let mut s = Scanner::from_str("1234561.25f h\u00e9llo");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"a"), &Int(1234));
+        assert_eq!(values.get(&~"b"), &Int(56));
+        assert_eq!(values.get(&~"c"), &Float(1.2));
+        assert_eq!(values.get(&~"d"), &Uint(0x5f));
+        assert_eq!(values.get(&~"e"), &Str(~"h\u00e9l"));
+        assert_eq!(values.get(&~"f"), &Str(~"lo"));
+    }
+
+    #[test]
+    fn test_hex() {
+        let pieces = parse_fmt("{a:#x} {b:#X} {c:x} {d:x}").unwrap();
+        let mut s = Scanner::from_str("0x1F 0Xab cD 0x");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"a"), &Uint(0x1f));
+        assert_eq!(values.get(&~"b"), &Uint(0xab));
+        assert_eq!(values.get(&~"c"), &Uint(0xcd));
+        assert_eq!(values.get(&~"d"), &Uint(0)); // no prefix without `#`
+        assert!(s.expect_str("x").is_ok());
+
+        let pieces = parse_fmt("{a:x}").unwrap();
+        let mut s = Scanner::from_str("10000000000000000");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("ffffffffffffffff");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap().get(&~"a"),
+                   &Uint(0xffffffffffffffff));
+        let mut s = Scanner::from_str("g");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, InvalidNumber);
+    }
+
+    #[test]
+    fn test_signed_hex() {
+        let pieces = parse_fmt("{a:x} {b:#X} {c:x}").unwrap();
+        let mut s = Scanner::from_str("-ff -0x80 7f");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"a"), &Int(-0xff));
+        assert_eq!(values.get(&~"b"), &Int(-0x80));
+        assert_eq!(values.get(&~"c"), &Uint(0x7f));
+
+        // the sign is mandatory with `+`
+        let pieces = parse_fmt("{a:+x} {b:+x}").unwrap();
+        let mut s = Scanner::from_str("+7f -1");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"a"), &Int(0x7f));
+        assert_eq!(values.get(&~"b"), &Int(-1));
+        let mut s = Scanner::from_str("7f");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, InvalidNumber);
+
+        let pieces = parse_fmt("{a:x}").unwrap();
+        let mut s = Scanner::from_str("-8000000000000001");
+        assert_eq!(interpret(&mut s, pieces.as_slice()).unwrap_err().kind, Overflow);
+    }
+
+    #[test]
+    fn test_fixed_width() {
+        let opts = ParseOptions { verbose: false, strict: false, columns: true };
+        let pieces = parse_fmt_with("{name:>6}{age:<4d}{city:.^8}", &opts).unwrap();
+        let mut s = Scanner::from_str("Bob     42..Tokyo.\n\u540d\u524d     7.\u6771\u4eac...");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"name"), &Str(~"Bob"));
+        assert_eq!(values.get(&~"age"), &Int(42));
+        assert_eq!(values.get(&~"city"), &Str(~"Tokyo"));
+        assert!(s.expect_str("\n").is_ok());
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"name"), &Str(~"\u540d\u524d"));
+        assert_eq!(values.get(&~"age"), &Int(7));
+        assert_eq!(values.get(&~"city"), &Str(~"\u6771\u4eac"));
+
+        let pieces = parse_fmt_with("{a:>4d}{b}", &opts).unwrap();
+        let mut s = Scanner::from_str("12x 3");
+        let err = interpret(&mut s, pieces.as_slice()).unwrap_err();
+        assert_eq!(err.kind, TrailingInput);
+        assert_eq!(err.name, Some(~"a"));
+        assert_eq!(err.offset, 2);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let pieces = parse_fmt("{a:y} {b:2y}{c:y}").unwrap();
+        let mut s = Scanner::from_str("\u00e9t\u00e9 abc");
+        let values = interpret(&mut s, pieces.as_slice()).unwrap();
+        assert_eq!(values.get(&~"a"), &Bytes(vec!(0xc3, 0xa9, 0x74, 0xc3, 0xa9)));
+        assert_eq!(values.get(&~"b"), &Bytes(vec!(0x61, 0x62)));
+        assert_eq!(values.get(&~"c"), &Bytes(vec!(0x63)));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let opts = InterpretOptions { ignore_case: true };
+        let pieces = parse_fmt("width={w:u}[ \u00e9t\u00e9={e:u}] {xs:*d sep \"and\"}").unwrap();
+        let mut s = Scanner::from_str("WIDTH=1 \u00c9T\u00c9=2 1AND2and3");
+        let values = interpret_with(&mut s, pieces.as_slice(), &opts).unwrap();
+        assert_eq!(values.get(&~"w"), &Uint(1));
+        assert_eq!(values.get(&~"e"), &Uint(2));
+        assert_eq!(values.get(&~"xs"), &List(vec!(Int(1), Int(2), Int(3))));
+
+        // the case matters without the option
+        let mut s = Scanner::from_str("WIDTH=1");
+        let err = interpret(&mut s, pieces.as_slice()).unwrap_err();
+        assert_eq!(err.kind, LiteralMismatch);
+        assert_eq!(err.offset, 0);
+
+        let mut s = Scanner::from_str("width=1 2AND3");
+        let mut values = HashMap::new();
+        assert_eq!(interpret_into_with(&mut s, pieces.as_slice(),
+                                       &InterpretOptions { ignore_case: false }, &mut values),
+                   Ok(()));
+        assert_eq!(values.get(&~"xs"), &List(vec!(Int(2))));
+        assert!(s.expect_str("AND3").is_ok());
+    }
+
+    #[test]
+    fn test_partial() {
+        let pieces = parse_fmt("{a:d}[ {b:d}] {c:*1..d sep \",\"} {d:d}").unwrap();
+        let mut s = Scanner::from_str("1 2 3,4 x");
+        let mut values = HashMap::new();
+        let err = interpret_into(&mut s, pieces.as_slice(), &mut values).unwrap_err();
+        assert_eq!(err.kind, InvalidNumber);
+        assert_eq!(err.name, Some(~"d"));
+        assert_eq!(err.piece, Some(5));
+        assert_eq!(err.assigned, vec!(~"a", ~"b", ~"c"));
+        assert_eq!(values.len(), 3);
+        assert_eq!(values.get(&~"c"), &List(vec!(Int(3), Int(4))));
+
+        // the group is rewound and not reported
+        let mut s = Scanner::from_str("1 x");
+        let mut values = HashMap::new();
+        let err = interpret_into(&mut s, pieces.as_slice(), &mut values).unwrap_err();
+        assert_eq!(err.piece, Some(3));
+        assert_eq!(err.assigned, vec!(~"a"));
+        assert_eq!(values.len(), 1);
+    }
+}
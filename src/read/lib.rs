@@ -11,4 +11,8 @@ pub mod parse;
 pub mod macros;
 pub mod buffer;
 pub mod rt;
+pub mod regex;
+pub mod deriving;
+pub mod interp;
+pub mod width;
 
@@ -1,29 +1,66 @@
-use collections::HashMap;
+use std::cmp;
+use collections::{HashMap, HashSet};
 
-use syntax::ast::{Name, SpannedIdent, TokenTree, Expr, Ty};
-use syntax::codemap::{Span, Spanned};
+use syntax::ast;
+use syntax::ast::{Name, SpannedIdent, TokenTree, Expr, Stmt, Ty};
+use syntax::codemap::{Span, Spanned, BytePos};
 use syntax::ext::base::*;
+use syntax::ext::build::AstBuilder;
 use syntax::parse;
 use syntax::parse::token;
+use syntax::parse::token::keywords;
+use syntax::print::pprust;
 
-use parse::parse_fmt;
+use parse::{parse_fmt_with, ParseOptions, unescape};
+use parse::{Piece, String, Whitespace, RequiredWhitespace, Argument, Group, ScanSpec};
+use parse::{ArgumentNamed, ArgumentNext, ArgumentIs, ArgumentSuppress};
+use parse::{AlignLeft, AlignRight, AlignCenter, AlignUnknown};
+use deriving;
 
-struct Args {
+/// Where the scanned value of the named argument goes.
+pub enum ArgTarget {
+    ArgType(@Ty),    // `name: Type`, returned as a new value
+    ArgPlace(@Expr), // `name => &mut place`, stored to the existing place
+    ArgInferred,     // `{}` without unnamed types in the list, inferred from the use
+}
+
+/// The variants of `lex!` sharing the same arguments.
+#[deriving(Eq)]
+pub enum MacroKind {
+    Lex,     // `lex!`, which reads from `&mut Buffer`, or `&mut rt::Scanner` with `in`
+    LexStr,  // `lex_str!`, which reads from `&str` and also returns the consumed length
+    LexIter, // `lex_iter!`, which returns `rt::Records` reading from `&mut Buffer`
+}
+
+pub struct Args {
     extra: @Expr,
+    scanner: bool, // `extra` is a caller-owned `&mut rt::Scanner`, keeping the lookahead
     fmtstr: @Expr,
+    alternatives: Vec<@Expr>, // `fmtstr` split by `|`
     ignore_case: bool,
-    named: HashMap<~str,(SpannedIdent,@Ty)>,
-    named_order: Vec<SpannedIdent>,
+    line_mode: bool, // reads a line first and requires the format to consume all of it
+    verbose: bool, // ignores whitespace and comments in the format
+    strict: bool, // requires at least one whitespace for whitespace in the format
+    columns: bool, // makes the arguments with the width fixed-width fields
+    into_places: bool,
+    kind: MacroKind, // set by the expander
+    named: HashMap<~str,(SpannedIdent,ArgTarget)>, // also has positional arguments
+    named_order: Vec<SpannedIdent>, // positional arguments go first
+    positional: Vec<(Span, @Ty)>, // unnamed types
+    npositional: uint, // set by `declare_positional`
 }
 
 fn parse_args(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Option<Args> {
-    let mut names = HashMap::<~str,(SpannedIdent,@Ty)>::new();
+    let mut names = HashMap::<~str,(SpannedIdent,ArgTarget)>::new();
     let mut order = Vec::new();
+    let mut positional = Vec::new();
+    let mut into_places = None;
 
     let mut p = parse::new_parser_from_tts(
         cx.parse_sess(), cx.cfg(), tts.iter().map(|x| (*x).clone()).collect());
 
-    // <macro-args> ::= <expr> ',' ...
+    // <macro-args> ::= ('in')? <expr> ',' ...
+    let scanner = p.eat_keyword(keywords::In);
     let extra = p.parse_expr();
     if !p.eat(&token::COMMA) {
         cx.span_err(sp, "expected token: `,`");
@@ -31,10 +68,17 @@ fn parse_args(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Option<Args> {
     }
 
     // ... <expr> ...
+    // <expr> ::= <str> | <expr> '|' <str>
     let fmtstr = p.parse_expr();
+    let mut alternatives = Vec::new();
+    flatten_alternatives(fmtstr, &mut alternatives);
 
     // ... (<ident>)? ...
     let mut ignore_case = false;
+    let mut line_mode = false;
+    let mut verbose = false;
+    let mut strict = false;
+    let mut columns = false;
     match p.token {
         token::IDENT(ident, false) => {
             let interned_name = token::get_ident(ident);
@@ -46,6 +90,22 @@ fn parse_args(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Option<Args> {
                         if ignore_case { dup = true; }
                         ignore_case = true;
                     }
+                    'l' => {
+                        if line_mode { dup = true; }
+                        line_mode = true;
+                    }
+                    'x' => {
+                        if verbose { dup = true; }
+                        verbose = true;
+                    }
+                    's' => {
+                        if strict { dup = true; }
+                        strict = true;
+                    }
+                    'c' => {
+                        if columns { dup = true; }
+                        columns = true;
+                    }
                     _ => {
                         cx.span_err(p.span, format!("unrecognized modifier `{}`", ch));
                         return None;
@@ -72,7 +132,29 @@ fn parse_args(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Option<Args> {
         }
         if p.token == token::EOF { break } // accept trailing commas
 
-        // <types> ::= ',' <ident> ':' 
+        // <types> ::= ',' <type> ...
+        let is_named = match p.token {
+            token::IDENT(..) => p.look_ahead(1, |t| *t == token::COLON ||
+                                                    *t == token::FAT_ARROW),
+            _ => false,
+        };
+        if !is_named {
+            let ty = p.parse_ty(false);
+            match into_places {
+                Some((true, prevsp)) => {
+                    cx.span_err(ty.span, "cannot mix `Type` and `name => place` arguments");
+                    cx.parse_sess.span_diagnostic.span_note(prevsp, "previously here");
+                    return None;
+                }
+                Some(..) => {}
+                None => { into_places = Some((false, ty.span)); }
+            }
+            positional.push((ty.span, ty));
+            continue;
+        }
+
+        // <types> ::= ',' <ident> ':' <type> ...
+        //           | ',' <ident> '=>' <expr> ...
         let (ident, identsp) = match p.token {
             token::IDENT(i, _) => {
                 p.bump();
@@ -86,8 +168,22 @@ fn parse_args(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Option<Args> {
         };
         let interned_name = token::get_ident(ident);
         let name = interned_name.get();
-        p.expect(&token::COLON);
-        let ty = p.parse_ty(false);
+        let target = if p.eat(&token::FAT_ARROW) {
+            ArgPlace(p.parse_expr())
+        } else {
+            p.expect(&token::COLON);
+            ArgType(p.parse_ty(false))
+        };
+        let is_place = match target { ArgPlace(..) => true, ArgType(..) => false };
+        match into_places {
+            Some((prev, prevsp)) if prev != is_place => {
+                cx.span_err(identsp, "cannot mix `name: Type` and `name => place` arguments");
+                cx.parse_sess.span_diagnostic.span_note(prevsp, "previously here");
+                return None;
+            }
+            Some(..) => {}
+            None => { into_places = Some((is_place, identsp)); }
+        }
         match names.find_equiv(&name) {
             None => {}
             Some(&(previd, _)) => {
@@ -98,45 +194,848 @@ fn parse_args(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Option<Args> {
         }
         let spanned = Spanned { node: ident, span: identsp };
         order.push(spanned);
-        names.insert(name.to_str(), (spanned, ty));
+        names.insert(name.to_str(), (spanned, target));
     }
 
-    Some(Args { extra: extra, fmtstr: fmtstr, ignore_case: ignore_case,
-                named: names, named_order: order })
+    let into_places = match into_places { Some((places, _)) => places, None => false };
+    Some(Args { extra: extra, scanner: scanner, fmtstr: fmtstr, alternatives: alternatives,
+                ignore_case: ignore_case, line_mode: line_mode, verbose: verbose,
+                strict: strict, columns: columns, into_places: into_places,
+                kind: Lex,
+                named: names, named_order: order, positional: positional, npositional: 0 })
 }
 
-fn expand(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> MacResult {
-    let args = match parse_args(cx, sp, tts) {
-        Some(args) => args,
-        None => return MRExpr(MacResult::raw_dummy_expr(sp))
+/// Collects the alternative formats `"a" | "b" | ...` in order.
+fn flatten_alternatives(expr: @Expr, out: &mut Vec<@Expr>) {
+    match expr.node {
+        ast::ExprBinary(ast::BiBitOr, lhs, rhs) => {
+            flatten_alternatives(lhs, out);
+            flatten_alternatives(rhs, out);
+        }
+        _ => { out.push(expr); }
+    }
+}
+
+/// Returns the suffix of the `rt::Scan` functions (`for_*` and `into_*`) for given scan type.
+fn scan_fn_suffix(ty: &str) -> Option<&'static str> {
+    match ty {
+        ""        => Some("read"),
+        "i"       => Some("integer"),
+        "d"       => Some("signed"),
+        "u"       => Some("unsigned"),
+        "c"       => Some("char"),
+        "o"       => Some("octal"),
+        "x" | "X" => Some("hex"),
+        "s"       => Some("string"),
+        "b"       => Some("binary"),
+        "f"       => Some("float"),
+        "e" | "E" => Some("exp"),
+        "y"       => Some("bytes"),
+        _         => None,
+    }
+}
+
+/// Returns the type used for suppressed arguments (`{*}`), which have no declared type.
+fn suppressed_ty(cx: &mut ExtCtxt, ty: &str) -> @Ty {
+    match ty {
+        "i" | "d"             => quote_ty!(cx, i64),
+        "u" | "o" | "x" | "X" |
+        "b"                   => quote_ty!(cx, u64),
+        "c"                   => quote_ty!(cx, char),
+        "f" | "e" | "E"       => quote_ty!(cx, f64),
+        "y"                   => quote_ty!(cx, Vec<u8>),
+        _                     => quote_ty!(cx, ~str),
+    }
+}
+
+/// A rough classification of declared types, used to catch obvious mismatches between
+/// the scan type and the declared type before the trait resolution does.
+#[deriving(Eq)]
+enum TyClass {
+    SignedTy,
+    UnsignedTy,
+    FloatTy,
+    CharTy,
+    StrTy,
+    OtherTy, // not known to the expander; left to the trait bounds
+}
+
+fn classify_ty(ty: &Ty) -> TyClass {
+    fn path_name(ty: &Ty) -> Option<token::InternedString> {
+        match ty.node {
+            ast::TyPath(ref path, None, _) if path.segments.len() == 1 => {
+                let seg = path.segments.get(0);
+                if seg.lifetimes.is_empty() && seg.types.is_empty() {
+                    Some(token::get_ident(seg.identifier))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    match ty.node {
+        ast::TyUniq(inner) | ast::TyRptr(_, ast::MutTy { ty: inner, .. }) => {
+            match path_name(inner) {
+                Some(ref name) if name.get() == "str" => StrTy,
+                _ => OtherTy,
+            }
+        }
+        _ => match path_name(ty) {
+            Some(name) => match name.get() {
+                "int" | "i8" | "i16" | "i32" | "i64" => SignedTy,
+                "uint" | "u8" | "u16" | "u32" | "u64" => UnsignedTy,
+                "f32" | "f64" => FloatTy,
+                "char" => CharTy,
+                "StrBuf" => StrTy,
+                _ => OtherTy,
+            },
+            None => OtherTy,
+        },
+    }
+}
+
+/// Checks if the declared type is `&str`, which can only borrow from the scanned string.
+fn is_borrowed_str(ty: &Ty) -> bool {
+    match ty.node {
+        ast::TyRptr(..) => classify_ty(ty) == StrTy,
+        _ => false,
+    }
+}
+
+/// Returns `T` from the declared type `Vec<T>`.
+fn vec_elem_ty(ty: &Ty) -> Option<@Ty> {
+    match ty.node {
+        ast::TyPath(ref path, None, _) => {
+            let seg = path.segments.last().unwrap();
+            if token::get_ident(seg.identifier).get() == "Vec" && seg.types.len() == 1 {
+                Some(*seg.types.get(0))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Checks if the declared type `ty` can be possibly scanned with the scan type.
+fn is_compatible_ty(scanty: &str, ty: &Ty) -> bool {
+    let class = classify_ty(ty);
+    if class == OtherTy { return true; }
+    match scanty {
+        "" => true,
+        "i" | "o" | "x" | "X" | "b" => class == SignedTy || class == UnsignedTy,
+        "d" => class == SignedTy,
+        "u" => class == UnsignedTy,
+        "c" => class == CharTy,
+        "s" => class == StrTy,
+        "f" | "e" | "E" => class == FloatTy,
+        "y" => false, // `Vec<u8>` is not classified
+        _ => true, // reported elsewhere
+    }
+}
+
+/// Translates a scan spec into an expression that reads one value from `__lex_s`,
+/// or a vector of values if the spec has a repetition.
+/// If `place` is given, the expression stores the value to it and counts the assignment.
+/// The errors from the expression are reported with `name` unless the value is suppressed.
+/// The pattern, if any, is added to `pats` and referred from `__lex_pats`.
+fn trans_scan(cx: &mut ExtCtxt, sp: Span, spec: &ScanSpec, name: Option<&str>,
+              place: Option<@Expr>, ignore_case: bool,
+              pats: &mut Vec<@Expr>) -> Option<@Expr> {
+    let suffix = if spec.class.is_some() || spec.regex.is_some() {
+        "capture"
+    } else {
+        match scan_fn_suffix(spec.ty) {
+            Some(suffix) => suffix,
+            None => {
+                cx.span_err(sp, format!("unknown scan type `{}`", spec.ty));
+                return None;
+            }
+        }
     };
 
-    let fmt = match expr_to_str(cx, args.fmtstr,
-                                "format argument must be a string literal.") {
-        Some((fmt, _)) => fmt,
-        None => return MRExpr(MacResult::raw_dummy_expr(sp))
+    let flags = spec.flags;
+    let fill = match spec.fill {
+        Some(ch) => cx.expr_some(sp, cx.expr_lit(sp, ast::LitChar(ch as u32))),
+        None => cx.expr_none(sp),
+    };
+    let align = cx.ident_of(match spec.align {
+        AlignLeft => "AlignLeft",
+        AlignRight => "AlignRight",
+        AlignCenter => "AlignCenter",
+        AlignUnknown => "AlignUnknown",
+    });
+    let width = match spec.width {
+        Some(width) => cx.expr_some(sp, cx.expr_uint(sp, width)),
+        None => cx.expr_none(sp),
+    };
+    let name = match name {
+        Some(name) => cx.expr_some(sp, cx.expr_str(sp, token::intern_and_get_ident(name))),
+        None => cx.expr_none(sp),
+    };
+
+    let mut stmts = Vec::new();
+    let for_f = cx.ident_of(format!("for_{}", suffix));
+    let into_f = cx.ident_of(format!("into_{}", suffix));
+    // the patterns have been verified, so they are parsed again at runtime (see `trans_patterns`)
+    let pat = match (&spec.class, &spec.regex) {
+        (&Some(ref class), _) => {
+            let src = cx.expr_str(sp, token::intern_and_get_ident(class.src));
+            Some(quote_expr!(cx,
+                ~::read::rt::CharClass::parse($src).unwrap() as ~::read::rt::Pattern))
+        }
+        (_, &Some(ref regex)) => {
+            let src = cx.expr_str(sp, token::intern_and_get_ident(regex.src));
+            Some(quote_expr!(cx,
+                ~::read::regex::Regex::new($src).unwrap() as ~::read::rt::Pattern))
+        }
+        (_, _) => None,
+    };
+    let (scan_one, scan_into) = match pat {
+        Some(pat) => {
+            let i = pats.len();
+            pats.push(pat);
+            (quote_expr!(cx, ::read::rt::Scan::$for_f(__lex_s, &**__lex_pats.get($i))),
+             place.clone().map(|place| quote_expr!(cx,
+                ::read::rt::Scan::$into_f(__lex_s, &**__lex_pats.get($i), $place))))
+        }
+        None => {
+            (quote_expr!(cx, ::read::rt::Scan::$for_f(__lex_s)),
+             place.clone().map(|place| quote_expr!(cx, ::read::rt::Scan::$into_f(__lex_s, $place))))
+        }
+    };
+    stmts.push(quote_stmt!(cx,
+        __lex_s.set_spec($name, $flags, $fill, ::read::rt::$align, $width);));
+
+    let repeated = match spec.repeat {
+        Some(ref repeat) => {
+            let min = repeat.min;
+            let max = match repeat.max {
+                Some(max) => cx.expr_some(sp, cx.expr_uint(sp, max)),
+                None => cx.expr_none(sp),
+            };
+            let sep = match repeat.sep {
+                Some(sep) => {
+                    let sep = token::intern_and_get_ident(unescape(sep));
+                    cx.expr_some(sp, cx.expr_str(sp, sep))
+                }
+                None => cx.expr_none(sp),
+            };
+            let ignore_case = cx.expr_bool(sp, ignore_case);
+            Some(quote_expr!(cx,
+                __lex_s.scan_repeat($min, $max, $sep, $ignore_case, |__lex_s| $scan_one)))
+        }
+        None => None,
+    };
+
+    let value = match (place, repeated, scan_into) {
+        (Some(place), Some(repeated), _) => {
+            stmts.push(quote_stmt!(cx, *$place = try!($repeated);));
+            stmts.push(quote_stmt!(cx, __lex_count += 1;));
+            None
+        }
+        (Some(_), None, Some(scan_into)) => {
+            stmts.push(quote_stmt!(cx, try!($scan_into);));
+            stmts.push(quote_stmt!(cx, __lex_count += 1;));
+            None
+        }
+        (None, Some(repeated), _) => Some(quote_expr!(cx, try!($repeated))),
+        (_, _, _) => Some(quote_expr!(cx, try!($scan_one))),
+    };
+    Some(cx.expr_block(cx.block(sp, stmts, value)))
+}
+
+/// Returns the key in `Args::named` (and the name of the variable) for the positional
+/// argument.
+pub fn positional_key(i: uint) -> ~str {
+    format!("__lex_arg{}", i)
+}
+
+/// Collects the keys in `Args::named` of all arguments in the pieces.
+pub fn collect_keys(pieces: &[Piece], out: &mut Vec<~str>) {
+    for piece in pieces.iter() {
+        match *piece {
+            Argument(ref arg) => match arg.position {
+                ArgumentNamed(name) => { out.push(name.to_owned()); }
+                ArgumentIs(i) => { out.push(positional_key(i)); }
+                ArgumentNext | ArgumentSuppress => {}
+            },
+            Group(ref group) => { collect_keys(group.as_slice(), out); }
+            String(..) | Whitespace | RequiredWhitespace => {}
+        }
+    }
+}
+
+/// Returns the number of positional arguments referenced by the pieces, i.e. the largest
+/// position plus one.
+fn count_positions(pieces: &[Piece]) -> uint {
+    pieces.iter().fold(0, |count, piece| match *piece {
+        Argument(ref arg) => match arg.position {
+            ArgumentIs(i) => cmp::max(count, i + 1),
+            _ => count,
+        },
+        Group(ref group) => cmp::max(count, count_positions(group.as_slice())),
+        _ => count,
+    })
+}
+
+/// Makes a vector of the patterns collected by `trans_scan`, to be bound to `__lex_pats`
+/// outside the scanning code. `lex_iter!` keeps it in `rt::Records`, so the patterns are
+/// parsed once instead of for every record.
+pub fn trans_patterns(cx: &mut ExtCtxt, sp: Span, pats: &[@Expr]) -> @Expr {
+    if pats.is_empty() { return quote_expr!(cx, Vec::new()); }
+    let n = pats.len();
+    let mut stmts = vec!(quote_stmt!(cx,
+        let mut __lex_pats: Vec<~::read::rt::Pattern> = Vec::with_capacity($n);));
+    for &pat in pats.iter() {
+        stmts.push(quote_stmt!(cx, __lex_pats.push($pat);));
+    }
+    cx.expr_block(cx.block(sp, stmts, Some(quote_expr!(cx, __lex_pats))))
+}
+
+/// Returns the span of the last argument in the list, or of the format without arguments.
+fn last_arg_span(args: &Args) -> Span {
+    let named = args.named_order.iter().map(|ident| ident.span);
+    let positional = args.positional.iter().map(|&(sp, _)| sp);
+    named.chain(positional).fold(args.fmtstr.span, |last, sp| {
+        if sp.hi > last.hi { sp } else { last }
+    })
+}
+
+/// Declares the variable for the argument, with the type annotation if any.
+fn decl_arg(cx: &mut ExtCtxt, sp: Span, mutbl: bool, ident: ast::Ident, ty: Option<@Ty>,
+            value: @Expr) -> @Stmt {
+    match ty {
+        Some(ty) => cx.stmt_let_typed(sp, mutbl, ident, ty, value),
+        None => cx.stmt_let(sp, mutbl, ident, value),
+    }
+}
+
+/// Translates the pieces of one format into statements scanning from `__lex_s`.
+/// If `optional` is set, the named values are assigned as `Some(..)` to the variables
+/// declared by the caller; otherwise they are declared here.
+/// The patterns are added to `pats`, which the caller binds with `trans_patterns`.
+/// Returns false if any error has been reported.
+pub fn trans_pieces(cx: &mut ExtCtxt, sp: Span, args: &Args, src: FmtSource, pieces: &[Piece],
+                    optional: bool, used: &mut HashSet<~str>, pats: &mut Vec<@Expr>,
+                    stmts: &mut Vec<@Stmt>) -> bool {
+    trans_pieces_at(cx, sp, args, src, pieces, optional, true, used, pats, stmts)
+}
+
+/// Same as `trans_pieces`, but only the top-level pieces report their indices to
+/// the scanner for the errors.
+fn trans_pieces_at(cx: &mut ExtCtxt, sp: Span, args: &Args, src: FmtSource, pieces: &[Piece],
+                   optional: bool, toplevel: bool, used: &mut HashSet<~str>,
+                   pats: &mut Vec<@Expr>, stmts: &mut Vec<@Stmt>) -> bool {
+    let fmtsp = src.span;
+    let mut ok = true;
+    for (i, piece) in pieces.iter().enumerate() {
+        if toplevel {
+            stmts.push(quote_stmt!(cx, __lex_s.set_piece($i);));
+        }
+        match *piece {
+            String(lit) if args.ignore_case => {
+                stmts.push(quote_stmt!(cx, try!(__lex_s.expect_str_ignore_case($lit));));
+            }
+            String(lit) => {
+                stmts.push(quote_stmt!(cx, try!(__lex_s.expect_str($lit));));
+            }
+            Whitespace => {
+                stmts.push(quote_stmt!(cx, try!(__lex_s.skip_whitespace());));
+            }
+            RequiredWhitespace => {
+                stmts.push(quote_stmt!(cx, try!(__lex_s.expect_whitespace());));
+            }
+            Argument(ref arg) => {
+                let (key, name) = match arg.position {
+                    ArgumentNamed(name) => (name.to_owned(), name.to_owned()),
+                    ArgumentIs(i) => (positional_key(i), i.to_str()),
+                    ArgumentNext => unreachable!(), // replaced by `parse_fmt`
+                    ArgumentSuppress => {
+                        let mut ty = suppressed_ty(cx, arg.scan.ty);
+                        if arg.scan.repeat.is_some() {
+                            ty = quote_ty!(cx, Vec<$ty>);
+                        }
+                        match trans_scan(cx, fmtsp, &arg.scan, None, None, args.ignore_case,
+                                         pats) {
+                            Some(scan) => { stmts.push(quote_stmt!(cx, let _: $ty = $scan;)); }
+                            None => { ok = false; }
+                        }
+                        continue;
+                    }
+                };
+                let (ident, ty) = match args.named.find(&key) {
+                    Some(&(ident, ArgType(ty))) => (ident.node, Some(ty)),
+                    Some(&(ident, ArgInferred)) => (ident.node, None),
+                    Some(&(_, ArgPlace(place))) => {
+                        used.insert(key);
+                        match trans_scan(cx, fmtsp, &arg.scan, Some(name.as_slice()),
+                                         Some(place), args.ignore_case, pats) {
+                            Some(scan) => { stmts.push(cx.stmt_expr(scan)); }
+                            None => { ok = false; }
+                        }
+                        continue;
+                    }
+                    None => {
+                        let argsp = src.span_of(cx, arg.start, arg.end);
+                        cx.span_err(argsp, format!("no type declared for argument `{}`", name));
+                        cx.parse_sess.span_diagnostic.span_note(
+                            last_arg_span(args),
+                            format!("expected `{}: <type>` in the argument list", name));
+                        ok = false;
+                        continue;
+                    }
+                };
+                used.insert(key);
+
+                match ty {
+                    Some(ty) => {
+                        let elemty = if arg.scan.repeat.is_some() {
+                            match vec_elem_ty(ty) {
+                                Some(elemty) => elemty,
+                                None => {
+                                    cx.span_err(src.span_of(cx, arg.start, arg.end),
+                                                format!("repeated argument `{}` should have \
+                                                         a type `Vec<T>`", name));
+                                    cx.parse_sess.span_diagnostic.span_note(ty.span,
+                                                                            "declared here");
+                                    ok = false;
+                                    continue;
+                                }
+                            }
+                        } else {
+                            ty
+                        };
+                        // (the patterns have no scan type and are left to the trait bounds)
+                        if !is_compatible_ty(arg.scan.ty, elemty) {
+                            cx.span_err(src.span_of(cx, arg.start, arg.end),
+                                        format!("scan type `{}` cannot be used for \
+                                                 argument `{}` of type `{}`",
+                                                arg.scan.ty, name, pprust::ty_to_str(ty)));
+                            cx.parse_sess.span_diagnostic.span_note(ty.span, "declared here");
+                            ok = false;
+                            continue;
+                        }
+                        // `lex!` and `lex_iter!` always read from `Buffer`
+                        if args.kind != LexStr && !args.scanner && is_borrowed_str(elemty) {
+                            cx.span_err(src.span_of(cx, arg.start, arg.end),
+                                        format!("argument `{}` of type `{}` cannot borrow \
+                                                 from the reader", name, pprust::ty_to_str(ty)));
+                            cx.parse_sess.span_diagnostic.span_note(
+                                ty.span, "use `~str`, or `lex_str!` to borrow from the string");
+                            ok = false;
+                            continue;
+                        }
+                    }
+                    None => {}
+                }
+
+                match trans_scan(cx, fmtsp, &arg.scan, Some(name.as_slice()), None,
+                                 args.ignore_case, pats) {
+                    Some(scan) => {
+                        if optional {
+                            stmts.push(quote_stmt!(cx, $ident = Some($scan);));
+                        } else {
+                            stmts.push(decl_arg(cx, sp, false, ident, ty, scan));
+                        }
+                    }
+                    None => { ok = false; }
+                }
+            }
+            Group(ref group) => {
+                // the arguments in the group are optional, so declare them in advance
+                // (unless they are already) and reset them when the group fails
+                let mut keys = Vec::new();
+                collect_keys(group.as_slice(), &mut keys);
+                let mut resets = Vec::new();
+                for key in keys.iter() {
+                    let (ident, ty) = match args.named.find(key) {
+                        Some(&(ident, ArgType(ty))) => {
+                            (ident.node, Some(quote_ty!(cx, Option<$ty>)))
+                        }
+                        Some(&(ident, ArgInferred)) => (ident.node, None),
+                        _ => { continue; }
+                    };
+                    if !optional {
+                        let none = cx.expr_none(sp);
+                        stmts.push(decl_arg(cx, sp, true, ident, ty, none));
+                    }
+                    resets.push(quote_stmt!(cx, $ident = None;));
+                }
+
+                let mut groupstmts = Vec::new();
+                if !trans_pieces_at(cx, sp, args, src, group.as_slice(), true, false,
+                                    used, pats, &mut groupstmts) {
+                    ok = false;
+                    continue;
+                }
+                let body = cx.expr_block(cx.block(sp, groupstmts, Some(quote_expr!(cx, Ok(())))));
+
+                let mut block = vec!(quote_stmt!(cx, let __lex_mark = __lex_s.mark();));
+                if args.into_places {
+                    // places cannot be restored, but the count can be
+                    block.push(quote_stmt!(cx, let __lex_count_saved = __lex_count;));
+                    resets.push(quote_stmt!(cx, __lex_count = __lex_count_saved;));
+                }
+                let reset = cx.expr_block(cx.block(sp, resets, None));
+                block.push(quote_stmt!(cx,
+                    let __lex_result = (|__lex_s: &mut ::read::rt::Scanner| $body)(__lex_s);));
+                block.push(quote_stmt!(cx, match __lex_result {
+                    Ok(()) => { __lex_s.release(__lex_mark); }
+                    Err(err) => {
+                        __lex_s.rewind(__lex_mark);
+                        if !::read::rt::is_mismatch(&err) { return Err(err); }
+                        $reset
+                    }
+                }));
+                stmts.push(cx.stmt_expr(cx.expr_block(cx.block(sp, block, None))));
+            }
+        }
+    }
+    ok
+}
+
+/// Makes a single value as is, or a tuple of values otherwise.
+fn trans_result(cx: &mut ExtCtxt, sp: Span, values: Vec<@Expr>) -> @Expr {
+    if values.len() == 1 {
+        *values.get(0)
+    } else {
+        cx.expr_tuple(sp, values)
+    }
+}
+
+/// Translates the parsed alternative formats into a `ScanResult` expression holding
+/// the named values in the declaration order, or the number of assigned places in
+/// the out-parameter mode. With multiple alternatives, the value is prepended with
+/// the index of the matched alternative and every named value is wrapped in `Option`.
+/// For `lex_str!`, the value is paired with the number of bytes consumed from the string.
+/// In the line mode, the value is checked against any leftover in the line.
+/// For `lex_iter!`, `rt::Records` yielding such values is returned instead.
+/// Returns `None` if any error has been reported.
+fn trans(cx: &mut ExtCtxt, sp: Span, args: &Args,
+         alts: &[(FmtSource, Vec<Piece>)]) -> Option<@Expr> {
+    let mut used = HashSet::new();
+    let mut failed = false;
+    let mut pats = Vec::new();
+    let mut stmts = Vec::new();
+
+    if args.kind == LexIter && args.into_places {
+        cx.span_err(sp, "`lex_iter!` cannot be used with `name => place` arguments");
+        return None;
+    }
+    if alts.len() > 1 && args.into_places {
+        cx.span_err(args.fmtstr.span,
+                    "alternative formats cannot be used with `name => place` arguments");
+        return None;
+    }
+
+    let idents: Vec<ast::Ident> = args.named_order.iter().map(|ident| ident.node).collect();
+    let result;
+    if alts.len() == 1 {
+        let &(src, ref pieces) = &alts[0];
+        if !trans_pieces(cx, sp, args, src, pieces.as_slice(), false,
+                         &mut used, &mut pats, &mut stmts) {
+            failed = true;
+        }
+        let values = idents.iter().map(|&ident| cx.expr_ident(sp, ident)).collect();
+        let value = trans_result(cx, sp, values);
+        result = quote_expr!(cx, Ok($value));
+    } else {
+        // every named value is optional as it may be missing from the matched alternative
+        for ident in args.named_order.iter() {
+            let ty = match args.named.find_equiv(&token::get_ident(ident.node).get()) {
+                Some(&(_, ArgType(ty))) => Some(quote_ty!(cx, Option<$ty>)),
+                Some(&(_, ArgInferred)) => None,
+                _ => { continue; }
+            };
+            let none = cx.expr_none(sp);
+            stmts.push(decl_arg(cx, sp, true, ident.node, ty, none));
+        }
+
+        for (i, &(src, ref pieces)) in alts.iter().enumerate() {
+            let mut altstmts = Vec::new();
+            if !trans_pieces(cx, sp, args, src, pieces.as_slice(), true,
+                             &mut used, &mut pats, &mut altstmts) {
+                failed = true;
+                continue;
+            }
+            let altbody = cx.expr_block(cx.block(sp, altstmts, Some(quote_expr!(cx, Ok(())))));
+
+            let mut values = vec!(cx.expr_uint(sp, i));
+            values.extend(idents.iter().map(|&ident| cx.expr_ident(sp, ident)));
+            let value = trans_result(cx, sp, values);
+
+            // the closure should be gone before the values are moved out
+            stmts.push(quote_stmt!(cx, {
+                let __lex_mark = __lex_s.mark();
+                let __lex_result = (|__lex_s: &mut ::read::rt::Scanner| $altbody)(__lex_s);
+                match __lex_result {
+                    Ok(()) => {
+                        __lex_s.release(__lex_mark);
+                        return Ok($value);
+                    }
+                    Err(err) => {
+                        __lex_s.rewind(__lex_mark);
+                        if !::read::rt::is_mismatch(&err) { return Err(err); }
+                    }
+                }
+            }));
+            for &ident in idents.iter() {
+                stmts.push(quote_stmt!(cx, $ident = None;));
+            }
+        }
+        // no alternative matches at the start
+        result = quote_expr!(cx,
+            Err(__lex_s.locate_literal(::read::rt::ScanError::new(::read::rt::LiteralMismatch))));
+    }
+
+    for (i, ident) in args.named_order.iter().enumerate() {
+        let name = token::get_ident(ident.node);
+        if !used.contains_equiv(&name.get()) {
+            if i < args.npositional {
+                cx.span_err(ident.span, format!("positional argument `{}` is never used \
+                                                 in the format", i));
+            } else {
+                cx.span_err(ident.span, format!("argument `{}` is never used in the format",
+                                                name.get()));
+            }
+            cx.parse_sess.span_diagnostic.span_note(args.fmtstr.span, "format is here");
+            failed = true;
+        }
+    }
+    if failed { return None; }
+
+    let has_pats = !pats.is_empty();
+    let pats = trans_patterns(cx, sp, pats.as_slice());
+
+    // note that any input looked ahead by the scanner is lost after the expansion,
+    // unless the scanner reads from the string and reports the consumed length,
+    // reads from the line read in advance, or is kept in `rt::Records` or by the caller (`in`).
+    let extra = args.extra;
+    if args.kind == LexIter && !args.line_mode {
+        let body = cx.expr_block(cx.block(sp, stmts, Some(result)));
+        return Some(quote_expr!(cx,
+            ::read::rt::Records::new(::read::rt::Scanner::new($extra), $pats,
+                                     |__lex_s: &mut ::read::rt::Scanner,
+                                      __lex_pats: &Vec<~::read::rt::Pattern>| $body)));
+    }
+
+    let mut block = Vec::new();
+    if args.kind != LexIter && has_pats {
+        block.push(quote_stmt!(cx, let __lex_pats = $pats;));
+    }
+    if args.line_mode {
+        block.push(quote_stmt!(cx,
+            let mut __lex_scanner = ::read::rt::Scanner::from_str(__lex_line.as_slice());));
+    } else if args.kind == LexStr {
+        block.push(quote_stmt!(cx,
+            let mut __lex_scanner = ::read::rt::Scanner::from_str($extra);));
+    } else if args.scanner {
+        block.push(quote_stmt!(cx, let __lex_scanner: &mut ::read::rt::Scanner = $extra;));
+    } else {
+        block.push(quote_stmt!(cx, let mut __lex_scanner = ::read::rt::Scanner::new($extra);));
+    }
+    let scanner = if args.scanner && !args.line_mode {
+        quote_expr!(cx, __lex_scanner)
+    } else {
+        quote_expr!(cx, &mut __lex_scanner)
     };
 
-    let pieces = match parse_fmt(fmt.get()) {
-        Ok(pieces) => pieces,
-        Err(err) => {
-            cx.span_err(args.fmtstr.span, err);
-            return MRExpr(MacResult::raw_dummy_expr(sp));
+    let mut result = if args.into_places {
+        block.push(quote_stmt!(cx, let mut __lex_count = 0u;));
+        let body = cx.expr_block(cx.block(sp, stmts, Some(quote_expr!(cx, Ok(())))));
+        block.push(quote_stmt!(cx,
+            let __lex_result = (|__lex_s: &mut ::read::rt::Scanner| $body)($scanner);));
+        quote_expr!(cx, ::read::rt::assigned_count(__lex_result, __lex_count))
+    } else {
+        let body = cx.expr_block(cx.block(sp, stmts, Some(result)));
+        block.push(quote_stmt!(cx,
+            let __lex_result = (|__lex_s: &mut ::read::rt::Scanner| $body)($scanner);));
+        quote_expr!(cx, __lex_result)
+    };
+    if args.line_mode {
+        result = quote_expr!(cx,
+            ::read::rt::check_line($result, &__lex_scanner, __lex_line.as_slice()));
+    }
+    if args.kind == LexStr {
+        let consumed = if args.line_mode {
+            quote_expr!(cx, __lex_line.len())
+        } else {
+            quote_expr!(cx, __lex_scanner.consumed())
+        };
+        result = quote_expr!(cx, $result.map(|v| (v, $consumed)));
+    }
+    let expr = cx.expr_block(cx.block(sp, block, Some(result)));
+    if !args.line_mode { return Some(expr); }
+
+    // the line is consumed as a whole, so the reader is at the next line even on errors
+    let line = match args.kind {
+        Lex if args.scanner => quote_expr!(cx, $extra.read_line()),
+        Lex => quote_expr!(cx, ::read::rt::read_line($extra)),
+        LexStr => quote_expr!(cx, ::read::rt::first_line($extra)),
+        LexIter => quote_expr!(cx, __lex_r.read_line()),
+    };
+    let expr = quote_expr!(cx, match $line {
+        Ok(__lex_line) => $expr,
+        Err(err) => Err(::read::rt::ScanError::from_io(err)),
+    });
+    if args.kind == LexIter {
+        Some(quote_expr!(cx,
+            ::read::rt::Records::new(::read::rt::Scanner::new($extra), $pats,
+                                     |__lex_r: &mut ::read::rt::Scanner,
+                                      __lex_pats: &Vec<~::read::rt::Pattern>| $expr)))
+    } else {
+        Some(expr)
+    }
+}
+
+/// Declares the positional arguments referenced by the formats (`{}` and `{0}`), which take
+/// the unnamed types in the argument list in order, or have inferred types if there are
+/// no unnamed types. Returns false if any error has been reported.
+fn declare_positional(cx: &mut ExtCtxt, args: &mut Args,
+                      alts: &[(FmtSource, Vec<Piece>)]) -> bool {
+    let count = alts.iter().fold(0, |count, &(_, ref pieces)| {
+        cmp::max(count, count_positions(pieces.as_slice()))
+    });
+
+    let inferred = args.positional.is_empty();
+    if inferred && count > 0 && args.into_places {
+        cx.span_err(args.fmtstr.span,
+                    "positional arguments cannot be used with `name => place` arguments");
+        return false;
+    }
+    if !inferred && count > args.positional.len() {
+        cx.span_err(args.fmtstr.span,
+                    format!("invalid reference to positional argument `{}` \
+                             (there are {} unnamed types)", count - 1, args.positional.len()));
+        return false;
+    }
+
+    let npositional = if inferred {count} else {args.positional.len()};
+    let mut order = Vec::new();
+    for i in range(0, npositional) {
+        let (span, target) = if inferred {
+            (args.fmtstr.span, ArgInferred)
+        } else {
+            let &(span, ty) = args.positional.get(i);
+            (span, ArgType(ty))
+        };
+        let ident = Spanned { node: cx.ident_of(positional_key(i)), span: span };
+        args.named.insert(positional_key(i), (ident, target));
+        order.push(ident);
+    }
+    order.push_all(args.named_order.as_slice());
+    args.named_order = order;
+    args.npositional = npositional;
+    true
+}
+
+/// A format string literal with its span, to point at a part of it in the errors.
+pub struct FmtSource<'a> {
+    span: Span,
+    fmt: &'a str, // the value of the literal
+    style: ast::StrStyle,
+}
+
+impl<'a> FmtSource<'a> {
+    /// Returns the span of the byte range `[start, end)` in the format. See `fmt_span`.
+    pub fn span_of(&self, cx: &ExtCtxt, start: uint, end: uint) -> Span {
+        fmt_span(cx, self.span, self.fmt, self.style, start, end)
+    }
+}
+
+/// Returns the span for the byte range `[start, end)` in the format string.
+/// The offsets can be mapped back only when the literal has no escape sequences,
+/// so the whole literal is used otherwise.
+pub fn fmt_span(cx: &ExtCtxt, fmtsp: Span, fmt: &str, style: ast::StrStyle,
+                start: uint, end: uint) -> Span {
+    let prefix = match style {
+        ast::CookedStr => 1, // `"`
+        ast::RawStr(n) => n + 2, // `r##"`
+    };
+    match cx.codemap().span_to_snippet(fmtsp) {
+        Some(snippet) => {
+            if snippet.len() < prefix + fmt.len() ||
+               snippet.slice(prefix, prefix + fmt.len()) != fmt {
+                return fmtsp;
+            }
         }
+        None => { return fmtsp; }
+    }
+    Span { lo: fmtsp.lo + BytePos((prefix + start) as u32),
+           hi: fmtsp.lo + BytePos((prefix + end) as u32),
+           expn_info: fmtsp.expn_info }
+}
+
+fn expand_lex(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree], kind: MacroKind) -> MacResult {
+    let mut args = match parse_args(cx, sp, tts) {
+        Some(args) => args,
+        None => return MRExpr(MacResult::raw_dummy_expr(sp))
     };
+    args.kind = kind;
+    if args.scanner && kind != Lex {
+        cx.span_err(args.extra.span, "only `lex!` can read from a caller-owned scanner with `in`");
+        return MRExpr(MacResult::raw_dummy_expr(sp));
+    }
 
-    /*
-    // start with the final result.
-    quote_expr!(cx, ::std::result::Ok(
-    */
+    let mut fmts = Vec::new();
+    for &fmtstr in args.alternatives.iter() {
+        match expr_to_str(cx, fmtstr, "format argument must be a string literal.") {
+            Some((fmt, style)) => { fmts.push((fmtstr.span, fmt, style)); }
+            None => return MRExpr(MacResult::raw_dummy_expr(sp))
+        }
+    }
 
-    println!("{:?}", pieces.as_slice());
+    let opts = ParseOptions { verbose: args.verbose, strict: args.strict,
+                              columns: args.columns };
+    let mut alts = Vec::new();
+    let mut failed = false;
+    for &(fmtsp, ref fmt, style) in fmts.iter() {
+        match parse_fmt_with(fmt.get(), &opts) {
+            Ok(pieces) => {
+                let src = FmtSource { span: fmtsp, fmt: fmt.get(), style: style };
+                alts.push((src, pieces));
+            }
+            Err(errs) => {
+                for err in errs.iter() {
+                    let errsp = fmt_span(cx, fmtsp, fmt.get(), style, err.start, err.end);
+                    cx.span_err(errsp, err.msg.as_slice());
+                }
+                failed = true;
+            }
+        }
+    }
+    if failed || !declare_positional(cx, &mut args, alts.as_slice()) {
+        return MRExpr(MacResult::raw_dummy_expr(sp));
+    }
+
+    match trans(cx, sp, &args, alts.as_slice()) {
+        Some(expr) => MRExpr(expr),
+        None => MRExpr(MacResult::raw_dummy_expr(sp)),
+    }
+}
+
+fn expand(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> MacResult {
+    expand_lex(cx, sp, tts, Lex)
+}
+
+fn expand_str(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> MacResult {
+    expand_lex(cx, sp, tts, LexStr)
+}
 
-    MRExpr(quote_expr!(cx, 1i))
+fn expand_iter(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> MacResult {
+    expand_lex(cx, sp, tts, LexIter)
 }
 
 #[macro_registrar]
 pub fn macro_registrar(register: |Name, SyntaxExtension|) {
     register(token::intern(&"lex"),
              NormalTT(~BasicMacroExpander { expander: expand, span: None }, None));
+    register(token::intern(&"lex_str"),
+             NormalTT(~BasicMacroExpander { expander: expand_str, span: None }, None));
+    register(token::intern(&"lex_iter"),
+             NormalTT(~BasicMacroExpander { expander: expand_iter, span: None }, None));
+    register(token::intern(&"read"), ItemDecorator(deriving::expand_read));
 }
@@ -1,23 +1,44 @@
+//! The parser of the format strings shared by `lex!`, `#[read]` and `interp`.
+//!
+//! `[` and `]` delimit an optional group, whose arguments are scanned as `Option<T>`
+//! and which rewinds the input to its start when it does not match. This is incompatible
+//! with the formats written before the groups, where the brackets were literals: they
+//! have to be escaped as `\[` and `\]` now, e.g. `\[{section}\]` for `[main]`.
+//!
+//! `\n`, `\r` and `\t` match exactly the newline, the carriage return and the tab, and
+//! `\s*` and `\s+` match optional and required whitespace (`\s` alone does so only in
+//! the verbose mode). Any other escaped character matches itself, as every escaped
+//! character did before; the formats which used these escapes for the letters themselves
+//! have to drop the backslash, and `\\n` still matches a backslash followed by `n`.
+
 use std::char;
 use std::str::CharRange;
+use std::strbuf::StrBuf;
 use collections::HashSet;
+use regex::Regex;
 
 #[deriving(Eq,Show)]
 pub enum Piece<'a> {
     String(&'a str),
-    Whitespace,
+    Whitespace, // any amount of whitespace, including none
+    RequiredWhitespace, // at least one whitespace
     Argument(Argument<'a>),
+    Group(Vec<Piece<'a>>), // optional; the input is rewound if any piece fails
 }
 
 #[deriving(Eq,Show)]
 pub struct Argument<'a> {
     position: Position<'a>,
     scan: ScanSpec<'a>,
+    start: uint, // the byte range `[start, end)` of the whole `{...}` in the format
+    end: uint,
 }
 
 #[deriving(Eq,Show)]
 pub enum Position<'a> {
     ArgumentNamed(&'a str),
+    ArgumentNext, // `{}`; `parse_fmt` replaces it with `ArgumentIs`
+    ArgumentIs(uint),
     ArgumentSuppress,
 }
 
@@ -28,6 +49,176 @@ pub struct ScanSpec<'a> {
     flags: uint,
     width: Option<uint>,
     ty: &'a str,
+    repeat: Option<Repeat<'a>>,
+    class: Option<CharClass<'a>>, // replaces the type if any
+    regex: Option<Regex<'a>>, // ditto
+}
+
+/// The repetition of the argument, which collects values into `Vec<T>`.
+#[deriving(Eq,Show)]
+pub struct Repeat<'a> {
+    min: uint,
+    max: Option<uint>,
+    sep: Option<&'a str>, // still escaped; `None` for any amount of whitespace
+}
+
+/// The character class (`[...]`), which captures a run of matching characters
+/// like C `%[...]`.
+#[deriving(Eq,Clone,Show)]
+pub struct CharClass<'a> {
+    src: &'a str, // the contents between the brackets, still escaped
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+#[deriving(Eq,Clone,Show)]
+pub enum ClassItem {
+    ClassRange(char, char), // inclusive; a single character has the same bounds
+    ClassCategory(Category, bool), // negated if true (`\P{...}`)
+}
+
+/// The Unicode categories and properties recognized in `\p{...}`.
+#[deriving(Eq,Clone,Show)]
+pub enum Category {
+    CatLetter,      // `L`; approximated with the `Alphabetic` property
+    CatUppercase,   // `Lu`; approximated with the `Uppercase` property
+    CatLowercase,   // `Ll`; approximated with the `Lowercase` property
+    CatNumber,      // `N`
+    CatDigit,       // `Nd`
+    CatControl,     // `Cc`
+    CatWhiteSpace,  // `White_Space`
+}
+
+impl Category {
+    fn from_name(name: &str) -> Option<Category> {
+        match name {
+            "L" => Some(CatLetter),
+            "Lu" => Some(CatUppercase),
+            "Ll" => Some(CatLowercase),
+            "N" => Some(CatNumber),
+            "Nd" => Some(CatDigit),
+            "Cc" => Some(CatControl),
+            "White_Space" => Some(CatWhiteSpace),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, ch: char) -> bool {
+        match *self {
+            CatLetter => char::is_alphabetic(ch),
+            CatUppercase => char::is_uppercase(ch),
+            CatLowercase => char::is_lowercase(ch),
+            CatNumber => char::is_alphanumeric(ch) && !char::is_alphabetic(ch),
+            CatDigit => char::is_digit(ch),
+            CatControl => char::is_control(ch),
+            CatWhiteSpace => char::is_whitespace(ch),
+        }
+    }
+}
+
+impl<'a> CharClass<'a> {
+    /// Parses the contents of `[...]`. The offsets in the error are relative to `src`.
+    ///
+    /// <class> ::= '^'? <item>+
+    /// <item>  ::= <char> ('-' <char>)? | '\\' ('p' | 'P') (<letter> | '{' <name> '}')
+    /// <char>  ::= any character except `\\` and `]` | '\\' <any character>
+    pub fn parse(src: &'a str) -> Result<CharClass<'a>, FormatError> {
+        let (negated, mut s) = if src.starts_with("^") {
+            (true, src.slice_from(1))
+        } else {
+            (false, src)
+        };
+        if s.is_empty() {
+            return Err(FormatError::new(~"an empty character class", 0, src.len()));
+        }
+
+        let mut items = Vec::new();
+        while !s.is_empty() {
+            let start = src.len() - s.len();
+            let (item, s_) = match parse_class_atom(s) {
+                Ok(ret) => ret,
+                Err((msg, t)) => {
+                    return Err(FormatError::new(msg, start, src.len() - t.len()));
+                }
+            };
+            s = s_;
+
+            // a trailing `-` is a literal
+            match item {
+                ClassRange(lo, _) if s.starts_with("-") && s.len() > 1 => {
+                    let (hi, s_) = match parse_class_atom(s.slice_from(1)) {
+                        Ok(ret) => ret,
+                        Err((msg, t)) => {
+                            return Err(FormatError::new(msg, start, src.len() - t.len()));
+                        }
+                    };
+                    let end = src.len() - s_.len();
+                    match hi {
+                        ClassRange(hi, _) if lo <= hi => { items.push(ClassRange(lo, hi)); }
+                        ClassRange(..) => {
+                            return Err(FormatError::new(~"an invalid character range",
+                                                        start, end));
+                        }
+                        ClassCategory(..) => {
+                            return Err(FormatError::new(~"a category cannot bound the range",
+                                                        start, end));
+                        }
+                    }
+                    s = s_;
+                }
+                item => { items.push(item); }
+            }
+        }
+
+        Ok(CharClass { src: src, negated: negated, items: items })
+    }
+
+    pub fn contains(&self, ch: char) -> bool {
+        let found = self.items.iter().any(|item| match *item {
+            ClassRange(lo, hi) => lo <= ch && ch <= hi,
+            ClassCategory(cat, negated) => cat.contains(ch) != negated,
+        });
+        found != self.negated
+    }
+}
+
+/// Parses a single character or category in the class, also used for the category escapes
+/// outside the class in `regex`. The error comes with the remaining string after
+/// the erroneous part.
+pub fn parse_class_atom<'a>(s: &'a str) -> Result<(ClassItem, &'a str), (~str, &'a str)> {
+    let (c, s) = s.slice_shift_char();
+    match c {
+        Some('\\') => {}
+        Some(c) => { return Ok((ClassRange(c, c), s)); }
+        None => unreachable!()
+    }
+
+    let (c, s) = s.slice_shift_char();
+    let negated = match c {
+        Some('p') => false,
+        Some('P') => true,
+        Some('n') => { return Ok((ClassRange('\n', '\n'), s)); }
+        Some('r') => { return Ok((ClassRange('\r', '\r'), s)); }
+        Some('t') => { return Ok((ClassRange('\t', '\t'), s)); }
+        Some(c) => { return Ok((ClassRange(c, c), s)); }
+        None => { return Err((~"an unfinished escape sequence", s)); }
+    };
+
+    let (name, rest) = if s.starts_with("{") {
+        match s.find('}') {
+            Some(i) => (s.slice(1, i), s.slice_from(i + 1)),
+            None => { return Err((~"an unclosed Unicode category", "")); }
+        }
+    } else if s.is_empty() {
+        return Err((~"a missing Unicode category", s));
+    } else {
+        let next = s.char_range_at(0).next;
+        (s.slice_to(next), s.slice_from(next))
+    };
+    match Category::from_name(name) {
+        Some(cat) => Ok((ClassCategory(cat, negated), rest)),
+        None => Err((format!("unknown Unicode category `{}`", name), rest)),
+    }
 }
 
 #[deriving(Eq,Show)]
@@ -35,6 +226,21 @@ pub enum Flags {
     FlagSignPlus,
     FlagSignMinus,
     FlagAlternate,
+    FlagFixedWidth, // not written in the format; set by `ParseOptions::columns`
+}
+
+/// An error in the format string, with the byte range `[start, end)` that caused it.
+#[deriving(Eq,Show)]
+pub struct FormatError {
+    msg: ~str,
+    start: uint,
+    end: uint,
+}
+
+impl FormatError {
+    fn new(msg: ~str, start: uint, end: uint) -> FormatError {
+        FormatError { msg: msg, start: start, end: end }
+    }
 }
 
 #[deriving(Eq,Show)]
@@ -75,27 +281,126 @@ fn parse_ident<'a>(s: &'a str) -> Option<(&'a str, &'a str)> {
     Some((s.slice_to(i), s.slice_from(i)))
 }
 
-// assumes that `s` does not contain the initial `{`
-fn parse_argument<'a>(s: &'a str) -> Result<(Argument<'a>, &'a str), ~str> {
+// parses a string quoted with `"`, returning the contents with escapes intact
+fn parse_quoted<'a>(s: &'a str) -> Option<(&'a str, &'a str)> {
+    if !s.starts_with("\"") { return None; }
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Some((s.slice(1, i), s.slice_from(i + 1)));
+        }
+    }
+    None
+}
+
+/// Finds the `close` character, skipping over escaped characters (`\\x` for any `x`)
+/// and character classes (`[...]`) if `classes` is set.
+pub fn skip_delimited(s: &str, close: char, classes: bool) -> Option<uint> {
+    let mut escaped = false;
+    let mut class = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if class {
+            if c == ']' { class = false; }
+        } else if c == close {
+            return Some(i);
+        } else if c == '[' && classes {
+            class = true;
+        }
+    }
+    None
+}
+
+// finds the `}` closing the argument, skipping over quoted strings, character classes
+// and regular expressions
+fn find_closing_brace(s: &str) -> Option<uint> {
+    let mut i = 0;
+    loop {
+        let j = match s.slice_from(i).find(&['"', '[', '/', '}']) {
+            Some(j) => i + j,
+            None => { return None; }
+        };
+        let (close, classes) = match s.char_at(j) {
+            '}' => { return Some(j); }
+            '"' => ('"', false),
+            '[' => (']', false),
+            _ => ('/', true),
+        };
+        match skip_delimited(s.slice_from(j + 1), close, classes) {
+            Some(k) => { i = j + 1 + k + 1; }
+            None => { return None; }
+        }
+    }
+}
+
+/// Removes backslash escapes (`\x` for any `x`) from the string.
+pub fn unescape(s: &str) -> ~str {
+    let mut ret = StrBuf::with_capacity(s.len());
+    let mut escaped = false;
+    for c in s.chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+        } else {
+            escaped = false;
+            ret.push_char(c);
+        }
+    }
+    ret.into_owned()
+}
+
+// assumes that `s` does not contain the initial `{` and is a suffix of `base`
+fn parse_argument<'a>(base: &'a str,
+                      s: &'a str) -> Result<(Argument<'a>, &'a str), FormatError> {
+    let open = base.len() - s.len() - 1; // the initial `{`
     let s = s.trim_left();
-    if s.is_empty() { return Err(~"a premature end of argument"); }
+    if s.is_empty() {
+        return Err(FormatError::new(~"a premature end of argument", open, base.len()));
+    }
 
-    // <scan> ::= '{' <name> ...
-    // <name> ::= IDENT | '*'
+    // <scan> ::= '{' <name>? ...
+    // <name> ::= IDENT | INTEGER | '*'
     let (pos, s) = match s.char_at(0) {
         '*' => (ArgumentSuppress, s.slice_from(1)),
+        ':' | '}' => (ArgumentNext, s),
+        '0'..'9' => match parse_uint(s) {
+            Some((i, s)) => (ArgumentIs(i), s),
+            None => {
+                let start = base.len() - s.len();
+                let end = start + s.find(|c: char| !('0' <= c && c <= '9')).unwrap_or(s.len());
+                return Err(FormatError::new(~"too large position in the argument",
+                                            start, end));
+            }
+        },
         _ => match parse_ident(s) {
             Some((id, s)) => (ArgumentNamed(id), s),
-            None => { return Err(~"a missing position in the argument"); }
+            None => {
+                let start = base.len() - s.len();
+                let end = start + s.char_range_at(0).next;
+                return Err(FormatError::new(~"a missing position in the argument",
+                                            start, end));
+            }
         },
     };
 
     // <scan> ::= ... (':' <spec>)? '}'
-    let idx = s.find('}'); // find the matching `}` first and verify it later
-    if idx.is_none() { return Err(~"a premature end of argument"); }
+    let idx = find_closing_brace(s); // find the matching `}` first and verify it later
+    if idx.is_none() {
+        return Err(FormatError::new(~"a premature end of argument", open, base.len()));
+    }
     let idx = idx.unwrap();
     let (spec, remaining) = (s.slice_to(idx), s.slice_from(idx + 1));
 
+    // the offset of `t` which should be a suffix of `spec`
+    let spec_end = base.len() - remaining.len() - 1;
+    let spec_offset = |t: &str| spec_end - t.len();
+
     // <scan-body> ::= ... (':' <spec>)?
     let scan;
     if spec.starts_with(":") {
@@ -106,7 +411,9 @@ fn parse_argument<'a>(s: &'a str) -> Result<(Argument<'a>, &'a str), ~str> {
         let s1 = s1.trim_left();
         let (c2, s2) = s1.slice_shift_char();
         let s2 = s2.trim_left();
+        // (`[` and `/` cannot be fill characters since they start the patterns)
         let (fill, align, s) = match (c1, c2) {
+            (Some('['), _) | (Some('/'), _) => (None, AlignUnknown, spec),
             (Some(fill), Some('<')) => (Some(fill), AlignLeft, s2),
             (Some(fill), Some('^')) => (Some(fill), AlignCenter, s2),
             (Some(fill), Some('>')) => (Some(fill), AlignRight, s2),
@@ -131,6 +438,36 @@ fn parse_argument<'a>(s: &'a str) -> Result<(Argument<'a>, &'a str), ~str> {
             s = s.slice_from(1).trim_left();
         }
 
+        // parse the optional repetition
+        // <repeat> ::= '*' (<min>? '..' <max>?)?
+        let (mut repeat, s) = if s.starts_with("*") {
+            let t = s.slice_from(1);
+            let (min, t1) = match parse_uint(t) {
+                Some((min, t1)) => (Some(min), t1),
+                None => (None, t),
+            };
+            if t1.starts_with("..") { // otherwise `min` is actually a width
+                let t2 = t1.slice_from(2);
+                let (max, t3) = match parse_uint(t2) {
+                    Some((max, t3)) => (Some(max), t3),
+                    None => (None, t2),
+                };
+                let min = min.unwrap_or(0);
+                match max {
+                    Some(max) if max == 0 || max < min => {
+                        return Err(FormatError::new(~"invalid repetition bounds",
+                                                    spec_offset(s), spec_offset(t3)));
+                    }
+                    _ => {}
+                }
+                (Some(Repeat { min: min, max: max, sep: None }), t3.trim_left())
+            } else {
+                (Some(Repeat { min: 0, max: None, sep: None }), t.trim_left())
+            }
+        } else {
+            (None, s)
+        };
+
         // parse the optional width
         let s = s.trim_left();
         let (width, s) = match parse_uint(s) {
@@ -138,71 +475,281 @@ fn parse_argument<'a>(s: &'a str) -> Result<(Argument<'a>, &'a str), ~str> {
             None => (None, s),
         };
 
+        // parse the optional character class in place of the type
+        // <class-spec> ::= '[' <class> ']'
+        let s = s.trim_left();
+        let (class, s) = if s.starts_with("[") {
+            let start = spec_offset(s);
+            let t = s.slice_from(1);
+            match skip_delimited(t, ']', false) {
+                Some(i) => match CharClass::parse(t.slice_to(i)) {
+                    Ok(class) => (Some(class), t.slice_from(i + 1)),
+                    Err(err) => {
+                        return Err(FormatError::new(err.msg, start + 1 + err.start,
+                                                    start + 1 + err.end));
+                    }
+                },
+                None => {
+                    return Err(FormatError::new(~"an unclosed character class",
+                                                start, start + 1));
+                }
+            }
+        } else {
+            (None, s)
+        };
+
+        // parse the optional regular expression in place of the type
+        // <regex-spec> ::= '/' <regex> '/'
+        let s = s.trim_left();
+        let (regex, s) = if class.is_none() && s.starts_with("/") {
+            let start = spec_offset(s);
+            let t = s.slice_from(1);
+            match skip_delimited(t, '/', true) {
+                Some(i) => match Regex::new(t.slice_to(i)) {
+                    Ok(regex) => (Some(regex), t.slice_from(i + 1)),
+                    Err(err) => {
+                        return Err(FormatError::new(err.msg, start + 1 + err.start,
+                                                    start + 1 + err.end));
+                    }
+                },
+                None => {
+                    return Err(FormatError::new(~"an unclosed regular expression",
+                                                start, start + 1));
+                }
+            }
+        } else {
+            (None, s)
+        };
+
         // parse the type name and verify if it is the end of argument
         let s = s.trim_left();
         let (ty, s) = match parse_ident(s) {
+            _ if class.is_some() || regex.is_some() => ("", s),
+            Some(("sep", s_)) if repeat.is_some() && s_.trim_left().starts_with("\"") => ("", s),
             Some((id, s)) => (id, s),
             None => ("", s),
         };
 
-        let s = s.trim();
-        if !s.is_empty() {
-            return Err(format!("invalid scan spec: {}", spec.trim()));
+        // parse the optional separator of the repetition
+        // <sep> ::= 'sep' '"' ... '"'
+        let mut s = s.trim_left();
+        match parse_ident(s) {
+            Some(("sep", t)) => {
+                let start = spec_offset(s);
+                if repeat.is_none() {
+                    return Err(FormatError::new(~"a separator without the repetition",
+                                                start, start + 3));
+                }
+                match parse_quoted(t.trim_left()) {
+                    Some((sep, t)) => {
+                        repeat.get_mut_ref().sep = Some(sep);
+                        s = t.trim_left();
+                    }
+                    None => {
+                        return Err(FormatError::new(~"a separator should be a quoted string",
+                                                    start, start + 3));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if !s.trim_right().is_empty() {
+            let start = spec_offset(s);
+            return Err(FormatError::new(format!("invalid scan spec: {}", spec.trim()),
+                                        start, start + s.trim_right().len()));
         }
-        scan = ScanSpec { fill: fill, align: align, flags: flags, width: width, ty: ty };
+        scan = ScanSpec { fill: fill, align: align, flags: flags, width: width, ty: ty,
+                          repeat: repeat, class: class, regex: regex };
     } else {
-        let spec = spec.trim();
-        if !spec.is_empty() {
-            return Err(format!("unexpected string after the position: {}", spec));
+        let rest = spec.trim_left();
+        if !rest.trim_right().is_empty() {
+            let start = spec_offset(rest);
+            return Err(FormatError::new(format!("unexpected string after the position: {}",
+                                                rest.trim_right()),
+                                        start, start + rest.trim_right().len()));
         }
-        scan = ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "" };
+        scan = ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "",
+                          repeat: None, class: None, regex: None };
     }
-    Ok((Argument { position: pos, scan: scan }, remaining))
+    Ok((Argument { position: pos, scan: scan, start: open, end: base.len() - remaining.len() },
+        remaining))
 }
 
-pub fn parse_fmt<'a>(mut s: &'a str) -> Result<Vec<Piece<'a>>, ~str> {
-    let mut pieces = Vec::new();
+/// Options changing the format grammar, set by the modifiers of `lex!`.
+#[deriving(Eq,Clone,Show)]
+pub struct ParseOptions {
+    /// The `x` modifier. Literal whitespace is ignored unless escaped (`\ `) or written
+    /// as `\s`, and `#` starts a comment running to the end of line.
+    verbose: bool,
+    /// The `s` modifier. Literal whitespace (and `\s`) requires at least one whitespace
+    /// in the input. `\s*` (optional) and `\s+` (required) can be used regardless of
+    /// the modifier, and `\n`, `\r` and `\t` match exactly those characters.
+    strict: bool,
+    /// The `c` modifier. Every argument with the width (marked with `FlagFixedWidth`) is
+    /// a field of exactly that many columns up to the newline, where the East Asian wide
+    /// characters take two columns. The value is scanned within the field after skipping
+    /// the fill on the side implied by the alignment, and should consume the whole field.
+    columns: bool,
+}
+
+impl ParseOptions {
+    fn whitespace<'a>(&self) -> Piece<'a> {
+        if self.strict {RequiredWhitespace} else {Whitespace}
+    }
+}
+
+/// Parses the format string into pieces. All errors found are returned at once;
+/// the parser resumes after the next `}` when it encounters an invalid argument.
+pub fn parse_fmt<'a>(fmt: &'a str) -> Result<Vec<Piece<'a>>, Vec<FormatError>> {
+    parse_fmt_with(fmt, &ParseOptions { verbose: false, strict: false, columns: false })
+}
+
+/// Same as `parse_fmt` but with the options.
+pub fn parse_fmt_with<'a>(fmt: &'a str,
+                          opts: &ParseOptions) -> Result<Vec<Piece<'a>>, Vec<FormatError>> {
+    let mut errors = Vec::new();
     let mut names = HashSet::new(); // tracking duplicate positions
+    let mut next = 0;
+    let (pieces, _) = parse_pieces(fmt, fmt, opts, false, &mut names, &mut next, &mut errors);
+    if errors.is_empty() {
+        Ok(pieces)
+    } else {
+        Err(errors)
+    }
+}
+
+// parses pieces until the end of `s` or the `]` closing the current group if `in_group`.
+// returns the pieces and the remaining string after `]`, which is `None` without `]`.
+// `names` tracks both names and positions (as decimal strings) in use, and `next` is
+// the position for the next `{}`.
+fn parse_pieces<'a>(fmt: &'a str, mut s: &'a str, opts: &ParseOptions, in_group: bool,
+                    names: &mut HashSet<~str>, next: &mut uint,
+                    errors: &mut Vec<FormatError>) -> (Vec<Piece<'a>>, Option<&'a str>) {
+    let mut pieces = Vec::new();
     let mut start = 0;
     loop {
-        let next = match s.slice_from(start).find(&['\\', '{', '}', ' ', '\t', '\r', '\n']) {
-            Some(next) => next + start,
+        let special = match s.slice_from(start).find(|c: char| match c {
+            '\\' | '{' | '}' | '[' | ']' | ' ' | '\t' | '\r' | '\n' => true,
+            '#' => opts.verbose,
+            _ => false,
+        }) {
+            Some(special) => special + start,
             None => { break; }
         };
-        if next > 0 {
-            pieces.push(String(s.slice_to(next)));
+        if special > 0 {
+            pieces.push(String(s.slice_to(special)));
         }
-        s = s.slice_from(next);
+        s = s.slice_from(special);
         let (c, s_) = s.slice_shift_char();
         s = s_;
         start = 0;
+        let offset = fmt.len() - s.len() - 1; // all special characters are one byte long
         match c {
             Some('\\') => {
                 // skip this letter and continue to the literals
                 if s.is_empty() {
-                    return Err(~"an unfinished escape sequence");
+                    errors.push(FormatError::new(~"an unfinished escape sequence",
+                                                 offset, offset + 1));
+                    break;
+                }
+                let (piece, len) = if s.starts_with("s*") {
+                    (Some(Whitespace), 2)
+                } else if s.starts_with("s+") {
+                    (Some(RequiredWhitespace), 2)
+                } else if opts.verbose && s.starts_with("s") {
+                    (Some(opts.whitespace()), 1)
+                } else if s.starts_with("n") {
+                    (Some(String("\n")), 1)
+                } else if s.starts_with("r") {
+                    (Some(String("\r")), 1)
+                } else if s.starts_with("t") {
+                    (Some(String("\t")), 1)
+                } else {
+                    (None, 0)
+                };
+                match piece {
+                    Some(piece) => {
+                        pieces.push(piece);
+                        s = s.slice_from(len);
+                        continue;
+                    }
+                    None => {}
                 }
                 start = s.char_range_at(0).next;
             }
-            Some('{') => {
-                let (arg, s_) = try!(parse_argument(s));
-                match arg.position {
-                    ArgumentNamed(name) => {
-                        if names.contains_equiv(&name) {
-                            return Err(format!("duplicate position `{}`", name));
+            Some('{') => match parse_argument(fmt, s) {
+                Ok((mut arg, s_)) => {
+                    if arg.position == ArgumentNext {
+                        arg.position = ArgumentIs(*next);
+                        *next += 1;
+                    }
+                    let name = match arg.position {
+                        ArgumentNamed(name) => Some(name.to_owned()),
+                        ArgumentIs(i) => Some(i.to_str()),
+                        ArgumentNext | ArgumentSuppress => None,
+                    };
+                    match name {
+                        Some(name) => {
+                            if names.contains(&name) {
+                                errors.push(FormatError::new(
+                                    format!("duplicate position `{}`", name),
+                                    offset, fmt.len() - s_.len()));
+                            }
+                            names.insert(name);
                         }
-                        names.insert(name.to_owned());
+                        None => {}
                     }
-                    ArgumentSuppress => {}
+                    if opts.columns && arg.scan.width.is_some() {
+                        arg.scan.flags |= 1 << FlagFixedWidth as uint;
+                    }
+                    pieces.push(Argument(arg));
+                    s = s_;
                 }
-                pieces.push(Argument(arg));
-                s = s_;
-            }
+                Err(err) => {
+                    errors.push(err);
+                    s = match s.find('}') {
+                        Some(idx) => s.slice_from(idx + 1),
+                        None => "",
+                    };
+                }
+            },
             Some('}') => {
-                return Err(~"unexpected `}` in the literal");
+                errors.push(FormatError::new(~"unexpected `}` in the literal",
+                                             offset, offset + 1));
+            }
+            Some('[') => {
+                let (group, s_) = parse_pieces(fmt, s, opts, true, names, next, errors);
+                match s_ {
+                    Some(s_) => {
+                        pieces.push(Group(group));
+                        s = s_;
+                    }
+                    None => {
+                        errors.push(FormatError::new(~"an unclosed optional group",
+                                                     offset, fmt.len()));
+                        s = "";
+                    }
+                }
+            }
+            Some(']') if in_group => {
+                return (pieces, Some(s));
+            }
+            Some(']') => {
+                errors.push(FormatError::new(~"unexpected `]` in the literal; \
+                                               escape it as `\\]`",
+                                             offset, offset + 1));
+            }
+            Some('#') => { // comments in the verbose mode
+                s = match s.find('\n') {
+                    Some(idx) => s.slice_from(idx + 1),
+                    None => "",
+                };
             }
             Some(_) => { // whitespaces
-                pieces.push(Whitespace);
+                if !opts.verbose {
+                    pieces.push(opts.whitespace());
+                }
                 s = s.trim_left();
             }
             None => unreachable!()
@@ -211,7 +758,7 @@ pub fn parse_fmt<'a>(mut s: &'a str) -> Result<Vec<Piece<'a>>, ~str> {
     if !s.is_empty() {
         pieces.push(String(s));
     }
-    Ok(pieces)
+    (pieces, if in_group {None} else {Some("")})
 }
 
 #[cfg(test)]
@@ -237,16 +784,61 @@ mod tests {
         assert!(parse_fmt("\\").is_err());
     }
 
+    #[test]
+    fn test_verbose() {
+        let verbose = |fmt| parse_fmt_with(fmt, &ParseOptions { verbose: true, strict: false,
+                                                                 columns: false });
+        assert!(verbose("a b\tc\n") == Ok(vec!(String("a"), String("b"), String("c"))));
+        assert!(verbose("a\\ b\\sc") == Ok(vec!(String("a"), String(" b"), Whitespace,
+                                                 String("c"))));
+        assert!(verbose("a # comment {x}\n  b # more") == Ok(vec!(String("a"), String("b"))));
+        assert!(verbose("a\\#b") == Ok(vec!(String("a"), String("#b"))));
+        assert!(verbose("[ \\s x ]") == Ok(vec!(Group(vec!(Whitespace, String("x"))))));
+
+        // `#` in the spec is a flag, not a comment
+        assert!(verbose("{x:#x}").is_ok());
+        assert!(parse_fmt("a # b") == Ok(vec!(String("a"), Whitespace, String("#"),
+                                              Whitespace, String("b"))));
+    }
+
+    #[test]
+    fn test_strict_whitespace() {
+        let strict = |fmt| parse_fmt_with(fmt, &ParseOptions { verbose: false, strict: true,
+                                                                columns: false });
+        assert!(strict("a b\t\nc") == Ok(vec!(String("a"), RequiredWhitespace, String("b"),
+                                              RequiredWhitespace, String("c"))));
+        assert!(strict("a\\s*b\\s+c") == Ok(vec!(String("a"), Whitespace, String("b"),
+                                                 RequiredWhitespace, String("c"))));
+        assert!(strict("a\\nb") == Ok(vec!(String("a"), String("\n"), String("b"))));
+
+        // the escapes are available without the modifier
+        assert!(parse_fmt("a\\s+b\\s*c") == Ok(vec!(String("a"), RequiredWhitespace,
+                                                    String("b"), Whitespace, String("c"))));
+        assert!(parse_fmt("\\r\\n\\t\\s") == Ok(vec!(String("\r"), String("\n"),
+                                                     String("\t"), String("s"))));
+
+        // the letters themselves are not escaped, and an escaped backslash stays literal
+        assert!(parse_fmt("n\\\\n") == Ok(vec!(String("n"), String("\\n"))));
+        assert!(parse_fmt("\\s*\\\\s*") == Ok(vec!(Whitespace, String("\\s*"))));
+
+        let both = |fmt| parse_fmt_with(fmt, &ParseOptions { verbose: true, strict: true,
+                                                              columns: false });
+        assert!(both("a \\s b") == Ok(vec!(String("a"), RequiredWhitespace, String("b"))));
+    }
+
     #[test]
     fn test_literal_and_spec() {
-        let placeholder = Argument(Argument {
+        let placeholder = |start, end| Argument(Argument {
             position: ArgumentNamed("x"),
-            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "" }
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "",
+                             repeat: None, class: None, regex: None },
+            start: start, end: end
         });
-        assert!(parse_fmt("{x}") == Ok(vec!(placeholder)));
-        assert!(parse_fmt("a{x}b") == Ok(vec!(String("a"), placeholder, String("b"))));
-        assert!(parse_fmt(" {x} ") == Ok(vec!(Whitespace, placeholder, Whitespace)));
-        assert!(parse_fmt("\\\\{x}\\\\") == Ok(vec!(String("\\"), placeholder, String("\\"))));
+        assert!(parse_fmt("{x}") == Ok(vec!(placeholder(0, 3))));
+        assert!(parse_fmt("a{x}b") == Ok(vec!(String("a"), placeholder(1, 4), String("b"))));
+        assert!(parse_fmt(" {x} ") == Ok(vec!(Whitespace, placeholder(1, 4), Whitespace)));
+        assert!(parse_fmt("\\\\{x}\\\\") ==
+                Ok(vec!(String("\\"), placeholder(2, 5), String("\\"))));
         assert!(parse_fmt("\\{x}").is_err());
     }
 
@@ -260,15 +852,29 @@ mod tests {
 
     #[test]
     fn test_spec_position() {
-        let arg_with_pos = |pos| Argument(Argument {
+        let arg_with_pos = |pos, start, end| Argument(Argument {
             position: pos,
-            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "" }
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "",
+                             repeat: None, class: None, regex: None },
+            start: start, end: end
         });
-        assert!(parse_fmt("{a}") == Ok(vec!(arg_with_pos(ArgumentNamed("a")))));
-        assert!(parse_fmt("{名前}") == Ok(vec!(arg_with_pos(ArgumentNamed("名前")))));
-        assert!(parse_fmt("{  名前  }") == Ok(vec!(arg_with_pos(ArgumentNamed("名前")))));
-        assert!(parse_fmt("{012}").is_err());
-        assert!(parse_fmt("{}").is_err());
+        assert!(parse_fmt("{a}") == Ok(vec!(arg_with_pos(ArgumentNamed("a"), 0, 3))));
+        assert!(parse_fmt("{名前}") == Ok(vec!(arg_with_pos(ArgumentNamed("名前"), 0, 8))));
+        assert!(parse_fmt("{  名前  }") ==
+                Ok(vec!(arg_with_pos(ArgumentNamed("名前"), 0, 12))));
+        assert!(parse_fmt("{012}") == Ok(vec!(arg_with_pos(ArgumentIs(12), 0, 5))));
+        assert!(parse_fmt("{ 3 }") == Ok(vec!(arg_with_pos(ArgumentIs(3), 0, 5))));
+        assert!(parse_fmt("{}") == Ok(vec!(arg_with_pos(ArgumentIs(0), 0, 2))));
+        assert!(parse_fmt("{} {}") == Ok(vec!(arg_with_pos(ArgumentIs(0), 0, 2), Whitespace,
+                                              arg_with_pos(ArgumentIs(1), 3, 5))));
+        assert!(parse_fmt("{1}{}") == Ok(vec!(arg_with_pos(ArgumentIs(1), 0, 3),
+                                              arg_with_pos(ArgumentIs(0), 3, 5))));
+        assert!(parse_fmt("{x}{}[{}]") == Ok(vec!(arg_with_pos(ArgumentNamed("x"), 0, 3),
+                                                  arg_with_pos(ArgumentIs(0), 3, 5),
+                                                  Group(vec!(arg_with_pos(ArgumentIs(1), 6, 8))))));
+        assert!(parse_fmt("{}{0}").is_err());
+        assert!(parse_fmt("{1}{}{}").is_err());
+        assert!(parse_fmt("{99999999999999999999999}").is_err());
         assert!(parse_fmt("{{}}").is_err());
         assert!(parse_fmt("{/}").is_err());
         assert!(parse_fmt("{-7}").is_err());
@@ -276,32 +882,36 @@ mod tests {
 
     #[test]
     fn test_spec_with_simple_type() {
-        let arg_with_ty = |ty| Argument(Argument {
+        let arg_with_ty = |ty, len| Argument(Argument {
             position: ArgumentNamed("x"),
-            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: ty }
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: ty,
+                             repeat: None, class: None, regex: None },
+            start: 0, end: len
         });
-        assert!(parse_fmt("{x}") == Ok(vec!(arg_with_ty(""))));
-        assert!(parse_fmt("{x:}") == Ok(vec!(arg_with_ty(""))));
-        assert!(parse_fmt("{x:a}") == Ok(vec!(arg_with_ty("a"))));
-        assert!(parse_fmt("{x : b }") == Ok(vec!(arg_with_ty("b"))));
-        assert!(parse_fmt("{x:いろいろ}") == Ok(vec!(arg_with_ty("いろいろ"))));
+        assert!(parse_fmt("{x}") == Ok(vec!(arg_with_ty("", 3))));
+        assert!(parse_fmt("{x:}") == Ok(vec!(arg_with_ty("", 4))));
+        assert!(parse_fmt("{x:a}") == Ok(vec!(arg_with_ty("a", 5))));
+        assert!(parse_fmt("{x : b }") == Ok(vec!(arg_with_ty("b", 8))));
+        assert!(parse_fmt("{x:いろいろ}") == Ok(vec!(arg_with_ty("いろいろ", 16))));
     }
 
     #[test]
     fn test_spec_with_flags() {
-        let arg_with_flags = |flags| Argument(Argument {
+        let arg_with_flags = |flags, len| Argument(Argument {
             position: ArgumentNamed("x"),
-            scan: ScanSpec { fill: None, align: AlignUnknown, flags: flags, width: None, ty: "foo" }
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: flags, width: None,
+                             ty: "foo", repeat: None, class: None, regex: None },
+            start: 0, end: len
         });
         let plus_mask = 1 << FlagSignPlus as uint;
         let minus_mask = 1 << FlagSignMinus as uint;
         let alternate_mask = 1 << FlagAlternate as uint;
-        assert!(parse_fmt("{x:foo}") == Ok(vec!(arg_with_flags(0))));
-        assert!(parse_fmt("{x:+foo}") == Ok(vec!(arg_with_flags(plus_mask))));
-        assert!(parse_fmt("{x:-foo}") == Ok(vec!(arg_with_flags(minus_mask))));
-        assert!(parse_fmt("{x:#foo}") == Ok(vec!(arg_with_flags(alternate_mask))));
-        assert!(parse_fmt("{x:+#foo}") == Ok(vec!(arg_with_flags(plus_mask | alternate_mask))));
-        assert!(parse_fmt("{x:-#foo}") == Ok(vec!(arg_with_flags(minus_mask | alternate_mask))));
+        assert!(parse_fmt("{x:foo}") == Ok(vec!(arg_with_flags(0, 7))));
+        assert!(parse_fmt("{x:+foo}") == Ok(vec!(arg_with_flags(plus_mask, 8))));
+        assert!(parse_fmt("{x:-foo}") == Ok(vec!(arg_with_flags(minus_mask, 8))));
+        assert!(parse_fmt("{x:#foo}") == Ok(vec!(arg_with_flags(alternate_mask, 8))));
+        assert!(parse_fmt("{x:+#foo}") == Ok(vec!(arg_with_flags(plus_mask | alternate_mask, 9))));
+        assert!(parse_fmt("{x:-#foo}") == Ok(vec!(arg_with_flags(minus_mask | alternate_mask, 9))));
         assert!(parse_fmt("{x:#+foo}").is_err());
         assert!(parse_fmt("{x:#-foo}").is_err());
         assert!(parse_fmt("{x:+-foo}").is_err());
@@ -313,43 +923,207 @@ mod tests {
 
     #[test]
     fn test_spec_with_alignment_and_fill() {
-        let arg_with_pad = |align, fill| Argument(Argument {
+        let arg_with_pad = |align, fill, len| Argument(Argument {
             position: ArgumentNamed("x"),
-            scan: ScanSpec { fill: fill, align: align, flags: 0, width: None, ty: "foo" }
+            scan: ScanSpec { fill: fill, align: align, flags: 0, width: None, ty: "foo",
+                             repeat: None, class: None, regex: None },
+            start: 0, end: len
         });
-        assert!(parse_fmt("{x:foo}") == Ok(vec!(arg_with_pad(AlignUnknown, None))));
-        assert!(parse_fmt("{x:>foo}") == Ok(vec!(arg_with_pad(AlignRight, None))));
-        assert!(parse_fmt("{x: > foo}") == Ok(vec!(arg_with_pad(AlignRight, None))));
-        assert!(parse_fmt("{x:_>foo}") == Ok(vec!(arg_with_pad(AlignRight, Some('_')))));
-        assert!(parse_fmt("{x:9>foo}") == Ok(vec!(arg_with_pad(AlignRight, Some('9')))));
-        assert!(parse_fmt("{x:>>foo}") == Ok(vec!(arg_with_pad(AlignRight, Some('>')))));
+        assert!(parse_fmt("{x:foo}") == Ok(vec!(arg_with_pad(AlignUnknown, None, 7))));
+        assert!(parse_fmt("{x:>foo}") == Ok(vec!(arg_with_pad(AlignRight, None, 8))));
+        assert!(parse_fmt("{x: > foo}") == Ok(vec!(arg_with_pad(AlignRight, None, 10))));
+        assert!(parse_fmt("{x:_>foo}") == Ok(vec!(arg_with_pad(AlignRight, Some('_'), 9))));
+        assert!(parse_fmt("{x:9>foo}") == Ok(vec!(arg_with_pad(AlignRight, Some('9'), 9))));
+        assert!(parse_fmt("{x:>>foo}") == Ok(vec!(arg_with_pad(AlignRight, Some('>'), 9))));
         assert!(parse_fmt("{x:>>>foo}").is_err());
-        assert!(parse_fmt("{x:^foo}") == Ok(vec!(arg_with_pad(AlignCenter, None))));
-        assert!(parse_fmt("{x: ^ foo}") == Ok(vec!(arg_with_pad(AlignCenter, None))));
-        assert!(parse_fmt("{x:_^foo}") == Ok(vec!(arg_with_pad(AlignCenter, Some('_')))));
-        assert!(parse_fmt("{x:9^foo}") == Ok(vec!(arg_with_pad(AlignCenter, Some('9')))));
-        assert!(parse_fmt("{x:^^foo}") == Ok(vec!(arg_with_pad(AlignCenter, Some('^')))));
+        assert!(parse_fmt("{x:^foo}") == Ok(vec!(arg_with_pad(AlignCenter, None, 8))));
+        assert!(parse_fmt("{x: ^ foo}") == Ok(vec!(arg_with_pad(AlignCenter, None, 10))));
+        assert!(parse_fmt("{x:_^foo}") == Ok(vec!(arg_with_pad(AlignCenter, Some('_'), 9))));
+        assert!(parse_fmt("{x:9^foo}") == Ok(vec!(arg_with_pad(AlignCenter, Some('9'), 9))));
+        assert!(parse_fmt("{x:^^foo}") == Ok(vec!(arg_with_pad(AlignCenter, Some('^'), 9))));
         assert!(parse_fmt("{x:^^^foo}").is_err());
-        assert!(parse_fmt("{x:<foo}") == Ok(vec!(arg_with_pad(AlignLeft, None))));
-        assert!(parse_fmt("{x: < foo}") == Ok(vec!(arg_with_pad(AlignLeft, None))));
-        assert!(parse_fmt("{x:_<foo}") == Ok(vec!(arg_with_pad(AlignLeft, Some('_')))));
-        assert!(parse_fmt("{x:9<foo}") == Ok(vec!(arg_with_pad(AlignLeft, Some('9')))));
-        assert!(parse_fmt("{x:<<foo}") == Ok(vec!(arg_with_pad(AlignLeft, Some('<')))));
+        assert!(parse_fmt("{x:<foo}") == Ok(vec!(arg_with_pad(AlignLeft, None, 8))));
+        assert!(parse_fmt("{x: < foo}") == Ok(vec!(arg_with_pad(AlignLeft, None, 10))));
+        assert!(parse_fmt("{x:_<foo}") == Ok(vec!(arg_with_pad(AlignLeft, Some('_'), 9))));
+        assert!(parse_fmt("{x:9<foo}") == Ok(vec!(arg_with_pad(AlignLeft, Some('9'), 9))));
+        assert!(parse_fmt("{x:<<foo}") == Ok(vec!(arg_with_pad(AlignLeft, Some('<'), 9))));
         assert!(parse_fmt("{x:<<<foo}").is_err());
     }
 
     #[test]
     fn test_spec_with_width() {
-        let arg_with_width = |width| Argument(Argument {
+        let arg_with_width = |width, len| Argument(Argument {
             position: ArgumentNamed("x"),
-            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: width, ty: "foo" }
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: width, ty: "foo",
+                             repeat: None, class: None, regex: None },
+            start: 0, end: len
         });
-        assert!(parse_fmt("{x:foo}") == Ok(vec!(arg_with_width(None))));
-        assert!(parse_fmt("{x:0foo}") == Ok(vec!(arg_with_width(Some(0)))));
-        assert!(parse_fmt("{x:042foo}") == Ok(vec!(arg_with_width(Some(42)))));
-        assert!(parse_fmt("{x: 42 foo}") == Ok(vec!(arg_with_width(Some(42)))));
+        assert!(parse_fmt("{x:foo}") == Ok(vec!(arg_with_width(None, 7))));
+        assert!(parse_fmt("{x:0foo}") == Ok(vec!(arg_with_width(Some(0), 8))));
+        assert!(parse_fmt("{x:042foo}") == Ok(vec!(arg_with_width(Some(42), 10))));
+        assert!(parse_fmt("{x: 42 foo}") == Ok(vec!(arg_with_width(Some(42), 11))));
         assert!(parse_fmt("{x:99999999999999999999999foo}").is_err());
         assert!(parse_fmt("{x: 4 2 foo}").is_err());
     }
-}
+    #[test]
+    fn test_spec_with_repeat() {
+        let arg_with_repeat = |width, repeat, len| Argument(Argument {
+            position: ArgumentNamed("xs"),
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: width, ty: "d",
+                             repeat: repeat, class: None, regex: None },
+            start: 0, end: len
+        });
+        let rep = |min, max, sep| Some(Repeat { min: min, max: max, sep: sep });
+        assert!(parse_fmt("{xs:*d}") == Ok(vec!(arg_with_repeat(None, rep(0, None, None), 7))));
+        assert!(parse_fmt("{xs: * d}") == Ok(vec!(arg_with_repeat(None, rep(0, None, None), 9))));
+        assert!(parse_fmt("{xs:*3d}") == Ok(vec!(arg_with_repeat(Some(3), rep(0, None, None), 8))));
+        assert!(parse_fmt("{xs:*1..d}") == Ok(vec!(arg_with_repeat(None, rep(1, None, None), 10))));
+        assert!(parse_fmt("{xs:*..4d}") ==
+                Ok(vec!(arg_with_repeat(None, rep(0, Some(4), None), 10))));
+        assert!(parse_fmt("{xs:*2..4 3d}") ==
+                Ok(vec!(arg_with_repeat(Some(3), rep(2, Some(4), None), 13))));
+        assert!(parse_fmt("{xs:*d sep \",\"}") ==
+                Ok(vec!(arg_with_repeat(None, rep(0, None, Some(",")), 15))));
+        assert!(parse_fmt("{xs:*d sep\"}\"}") ==
+                Ok(vec!(arg_with_repeat(None, rep(0, None, Some("}")), 14))));
+        assert!(parse_fmt("{xs:*d sep \"\\\"\"}") ==
+                Ok(vec!(arg_with_repeat(None, rep(0, None, Some("\\\"")), 16))));
+        assert!(parse_fmt("{xs:*2..1d}").is_err());
+        assert!(parse_fmt("{xs:*..0d}").is_err());
+        assert!(parse_fmt("{xs:d sep \",\"}").is_err());
+        assert!(parse_fmt("{xs:*d sep ,}").is_err());
+        assert!(parse_fmt("{xs:*d sep \",}").is_err());
+        assert!(parse_fmt("{xs:**d}").is_err());
+
+        assert_eq!(unescape("a\\,b\\\\"), ~"a,b\\");
+    }
+
+    #[test]
+    fn test_spec_with_class() {
+        fn class<'a>(fmt: &'a str) -> Option<CharClass<'a>> {
+            match parse_fmt(fmt) {
+                Ok(pieces) => match pieces.as_slice() {
+                    [Argument(ref arg)] => arg.scan.class.clone(),
+                    _ => None,
+                },
+                Err(..) => None,
+            }
+        }
+        let cls = |src, negated, items| Some(CharClass { src: src, negated: negated,
+                                                         items: items });
+        assert_eq!(class("{x:[a-z_]}"),
+                   cls("a-z_", false, vec!(ClassRange('a', 'z'), ClassRange('_', '_'))));
+        assert_eq!(class("{x: 10 [^,]}"), cls("^,", true, vec!(ClassRange(',', ','))));
+        assert_eq!(class("{x:[-a-]}"), cls("-a-", false, vec!(ClassRange('-', '-'),
+                                                            ClassRange('a', 'a'),
+                                                            ClassRange('-', '-'))));
+        assert_eq!(class("{x:[\\]\\-\\t]}"),
+                   cls("\\]\\-\\t", false, vec!(ClassRange(']', ']'), ClassRange('-', '-'),
+                                                ClassRange('\t', '\t'))));
+        assert_eq!(class("{x:[\\pL\\P{Nd}{}]}"),
+                   cls("\\pL\\P{Nd}{}", false, vec!(ClassCategory(CatLetter, false),
+                                                    ClassCategory(CatDigit, true),
+                                                    ClassRange('{', '{'),
+                                                    ClassRange('}', '}'))));
+        assert_eq!(class("{x:[가-힣]}"), cls("가-힣", false, vec!(ClassRange('가', '힣'))));
+        assert_eq!(class("{x:d}"), None);
+
+        assert!(parse_fmt("{xs:*[a-z] sep \",\"}").is_ok());
+        assert!(parse_fmt("{x:[]}").is_err());
+        assert!(parse_fmt("{x:[^]}").is_err());
+        assert!(parse_fmt("{x:[a-z}").is_err());
+        assert!(parse_fmt("{x:[z-a]}").is_err());
+        assert!(parse_fmt("{x:[a-\\pL]}").is_err());
+        assert!(parse_fmt("{x:[\\p{Foo}]}").is_err());
+        assert!(parse_fmt("{x:[\\p{L]}").is_err());
+        assert!(parse_fmt("{x:[a]d}").is_err());
 
+        let digits = CharClass::parse("0-9\\pL").unwrap();
+        assert!(digits.contains('5') && digits.contains('é') && !digits.contains(','));
+        let not_comma = CharClass::parse("^,").unwrap();
+        assert!(not_comma.contains('a') && not_comma.contains(' ') && !not_comma.contains(','));
+    }
+
+    #[test]
+    fn test_spec_with_regex() {
+        fn regex_src<'a>(fmt: &'a str) -> Option<&'a str> {
+            match parse_fmt(fmt) {
+                Ok(pieces) => match pieces.as_slice() {
+                    [Argument(ref arg)] => arg.scan.regex.as_ref().map(|re| re.src),
+                    _ => None,
+                },
+                Err(..) => None,
+            }
+        }
+        assert_eq!(regex_src("{v:/[0-9]+(\\.[0-9]+)*/}"), Some("[0-9]+(\\.[0-9]+)*"));
+        assert_eq!(regex_src("{v: 8 /a{2,3}|[/}]/}"), Some("a{2,3}|[/}]"));
+        assert_eq!(regex_src("{v:/a\\/b/}"), Some("a\\/b"));
+        assert_eq!(regex_src("{v:/<d}"), None);
+
+        assert!(parse_fmt("{vs:*/[a-z]+/ sep \",\"}").is_ok());
+        assert!(parse_fmt("{v:/a/d}").is_err());
+        assert!(parse_fmt("{v:/a}").is_err());
+        assert!(parse_fmt("{v:/(a/}").is_err());
+        assert!(parse_fmt("{v:[a]/a/}").is_err());
+        assert_eq!(parse_fmt("{v:/ab)/}").unwrap_err().get(0).start, 6);
+    }
+
+    #[test]
+    fn test_group() {
+        let placeholder = |name, start, end| Argument(Argument {
+            position: ArgumentNamed(name),
+            scan: ScanSpec { fill: None, align: AlignUnknown, flags: 0, width: None, ty: "",
+                             repeat: None, class: None, regex: None },
+            start: start, end: end
+        });
+        assert!(parse_fmt("[]") == Ok(vec!(Group(vec!()))));
+        assert!(parse_fmt("a[b{x}]c") == Ok(vec!(String("a"),
+                                                 Group(vec!(String("b"), placeholder("x", 3, 6))),
+                                                 String("c"))));
+        assert!(parse_fmt("{m}[ (pid {pid})]") ==
+                Ok(vec!(placeholder("m", 0, 3),
+                        Group(vec!(Whitespace, String("(pid"), Whitespace,
+                                   placeholder("pid", 10, 15), String(")"))))));
+        assert!(parse_fmt("[{x}[{y}]]") ==
+                Ok(vec!(Group(vec!(placeholder("x", 1, 4), Group(vec!(placeholder("y", 5, 8))))))));
+        assert!(parse_fmt("\\[x\\]") == Ok(vec!(String("[x"), String("]"))));
+
+        // the escaped brackets are literals, also within the groups
+        assert!(parse_fmt("\\[{x}\\]") == Ok(vec!(String("["), placeholder("x", 2, 5),
+                                                    String("]"))));
+        assert!(parse_fmt("[\\]]") == Ok(vec!(Group(vec!(String("]"))))));
+        assert!(parse_fmt("[").is_err());
+        assert!(parse_fmt("]").is_err());
+        assert!(parse_fmt("[{x}").is_err());
+        assert!(parse_fmt("[[{x}]").is_err());
+        assert!(parse_fmt("[{x}]]").is_err());
+        assert!(parse_fmt("{x}[{x}]").is_err());
+    }
+
+    #[test]
+    fn test_error_spans() {
+        fn spans(fmt: &str) -> Vec<(uint, uint)> {
+            match parse_fmt(fmt) {
+                Ok(_) => Vec::new(),
+                Err(errs) => errs.iter().map(|e| (e.start, e.end)).collect(),
+            }
+        }
+        assert_eq!(spans("{x}"), vec!());
+        assert_eq!(spans("ab}c"), vec!((2, 3)));
+        assert_eq!(spans("{x:#+foo}"), vec!((4, 8)));
+        assert_eq!(spans("{x: # + d}"), vec!((6, 9)));
+        assert_eq!(spans("{x y}"), vec!((3, 4)));
+        assert_eq!(spans("{x}{x}"), vec!((3, 6)));
+        assert_eq!(spans("{/}"), vec!((1, 2)));
+        assert_eq!(spans("{}{0:d}"), vec!((2, 7)));
+        assert_eq!(spans("a\\"), vec!((1, 2)));
+        assert_eq!(spans("{x"), vec!((0, 2)));
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        let errs = parse_fmt("} {/} {x:+-d} \\").unwrap_err();
+        let spans: Vec<(uint, uint)> = errs.iter().map(|e| (e.start, e.end)).collect();
+        assert_eq!(spans, vec!((0, 1), (3, 4), (10, 12), (14, 15)));
+    }
+}
@@ -0,0 +1,415 @@
+use std::cmp;
+use std::io::IoResult;
+use collections::HashSet;
+use parse::{FormatError, CharClass, ClassRange, ClassCategory, skip_delimited};
+use parse::parse_class_atom;
+use parse::{CatDigit, CatLetter, CatWhiteSpace};
+use rt::Pattern;
+
+/// The maximum number of instructions in the compiled regular expression.
+/// This mostly limits the nested counted repetitions like `(a{100}){100}`.
+static MAX_PROGRAM_SIZE: uint = 10000;
+
+/// A small regular expression for `{x:/.../}`, matched by the backtracking.
+///
+/// <alt>  ::= <cat> ('|' <cat>)*
+/// <cat>  ::= (<atom> <quantifier>?)*
+/// <atom> ::= '(' <alt> ')' | '[' <class> ']' | '.' | '\' <any character> | <character>
+/// <quantifier> ::= '*' | '+' | '?' | '{' <min> (',' <max>?)? '}'
+///
+/// The escapes `\d`, `\w` and `\s` (and their negations `\D`, `\W` and `\S`) are
+/// available in addition to the escapes in the character class. `.` matches every
+/// character except for the newline. The matching is always anchored at the beginning.
+#[deriving(Eq,Clone,Show)]
+pub struct Regex<'a> {
+    src: &'a str, // still escaped
+    prog: Vec<Inst<'a>>,
+}
+
+#[deriving(Eq,Clone,Show)]
+pub enum Inst<'a> {
+    InstChar(char),
+    InstClass(CharClass<'a>),
+    InstAny,
+    InstSplit(uint, uint), // tries the first and then the second
+    InstJmp(uint),
+    InstMatch,
+}
+
+enum Node<'a> {
+    NodeChar(char),
+    NodeClass(CharClass<'a>),
+    NodeAny,
+    NodeCat(Vec<Node<'a>>),
+    NodeAlt(Vec<Node<'a>>),
+    NodeRepeat(~Node<'a>, uint, Option<uint>),
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: uint,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        if self.pos < self.src.len() {
+            Some(self.src.char_at(self.pos))
+        } else {
+            None
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek();
+        match ch {
+            Some(ch) => { self.pos += ch.len_utf8_bytes(); }
+            None => {}
+        }
+        ch
+    }
+
+    fn error<T>(&self, msg: ~str, start: uint) -> Result<T, FormatError> {
+        Err(FormatError { msg: msg, start: start, end: self.pos })
+    }
+
+    fn parse_alt(&mut self) -> Result<Node<'a>, FormatError> {
+        let mut alts = vec!(try!(self.parse_cat()));
+        while self.peek() == Some('|') {
+            self.bump();
+            alts.push(try!(self.parse_cat()));
+        }
+        if alts.len() == 1 {
+            Ok(alts.pop().unwrap())
+        } else {
+            Ok(NodeAlt(alts))
+        }
+    }
+
+    fn parse_cat(&mut self) -> Result<Node<'a>, FormatError> {
+        let mut nodes = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some('|') | Some(')') => { break; }
+                _ => {}
+            }
+            let start = self.pos;
+            let atom = try!(self.parse_atom());
+            nodes.push(try!(self.parse_quantifier(atom, start)));
+        }
+        Ok(NodeCat(nodes))
+    }
+
+    fn parse_atom(&mut self) -> Result<Node<'a>, FormatError> {
+        let start = self.pos;
+        match self.bump() {
+            Some('(') => {
+                let node = try!(self.parse_alt());
+                if self.bump() != Some(')') {
+                    return self.error(~"an unclosed group", start);
+                }
+                Ok(node)
+            }
+            Some('[') => {
+                let rest = self.src.slice_from(self.pos);
+                match skip_delimited(rest, ']', false) {
+                    Some(i) => match CharClass::parse(rest.slice_to(i)) {
+                        Ok(class) => {
+                            self.pos += i + 1;
+                            Ok(NodeClass(class))
+                        }
+                        Err(err) => {
+                            Err(FormatError { msg: err.msg, start: self.pos + err.start,
+                                              end: self.pos + err.end })
+                        }
+                    },
+                    None => self.error(~"an unclosed character class", start),
+                }
+            }
+            Some('.') => Ok(NodeAny),
+            Some('\\') => {
+                let (negated, items) = match self.bump() {
+                    Some('d') => (false, vec!(ClassCategory(CatDigit, false))),
+                    Some('D') => (true, vec!(ClassCategory(CatDigit, false))),
+                    Some('w') => (false, vec!(ClassCategory(CatLetter, false),
+                                              ClassCategory(CatDigit, false),
+                                              ClassRange('_', '_'))),
+                    Some('W') => (true, vec!(ClassCategory(CatLetter, false),
+                                             ClassCategory(CatDigit, false),
+                                             ClassRange('_', '_'))),
+                    Some('s') => (false, vec!(ClassCategory(CatWhiteSpace, false))),
+                    Some('S') => (true, vec!(ClassCategory(CatWhiteSpace, false))),
+                    Some('p') | Some('P') => {
+                        let src = self.src;
+                        match parse_class_atom(src.slice_from(start)) {
+                            Ok((item, rest)) => {
+                                self.pos = src.len() - rest.len();
+                                (false, vec!(item))
+                            }
+                            Err((msg, rest)) => {
+                                self.pos = src.len() - rest.len();
+                                return self.error(msg, start);
+                            }
+                        }
+                    }
+                    Some('n') => { return Ok(NodeChar('\n')); }
+                    Some('r') => { return Ok(NodeChar('\r')); }
+                    Some('t') => { return Ok(NodeChar('\t')); }
+                    Some(ch) => { return Ok(NodeChar(ch)); }
+                    None => { return self.error(~"an unfinished escape sequence", start); }
+                };
+                let src = self.src.slice(start, self.pos);
+                Ok(NodeClass(CharClass { src: src, negated: negated, items: items }))
+            }
+            Some('*') | Some('+') | Some('?') | Some('{') => {
+                self.error(~"a repetition without the target", start)
+            }
+            Some(ch) => Ok(NodeChar(ch)),
+            None => unreachable!(),
+        }
+    }
+
+    fn parse_count(&mut self) -> Option<uint> {
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some('0'..'9') => { self.bump(); }
+                _ => { break; }
+            }
+        }
+        from_str(self.src.slice(start, self.pos))
+    }
+
+    fn parse_quantifier(&mut self, atom: Node<'a>,
+                        start: uint) -> Result<Node<'a>, FormatError> {
+        let (min, max) = match self.peek() {
+            Some('*') => { self.bump(); (0, None) }
+            Some('+') => { self.bump(); (1, None) }
+            Some('?') => { self.bump(); (0, Some(1)) }
+            Some('{') => {
+                self.bump();
+                let min = match self.parse_count() {
+                    Some(min) => min,
+                    None => { return self.error(~"a missing repetition count", start); }
+                };
+                let max = if self.peek() == Some(',') {
+                    self.bump();
+                    self.parse_count()
+                } else {
+                    Some(min)
+                };
+                if self.bump() != Some('}') {
+                    return self.error(~"an unclosed repetition count", start);
+                }
+                if max.map_or(false, |max| max < min) {
+                    return self.error(~"invalid repetition bounds", start);
+                }
+                (min, max)
+            }
+            _ => { return Ok(atom); }
+        };
+
+        match self.peek() {
+            Some('*') | Some('+') | Some('?') | Some('{') => {
+                self.bump();
+                return self.error(~"a repetition of the repetition", start);
+            }
+            _ => {}
+        }
+        Ok(NodeRepeat(~atom, min, max))
+    }
+}
+
+// returns the number of instructions for the node, or anything larger than
+// `MAX_PROGRAM_SIZE` if it is too large
+fn program_size(node: &Node) -> uint {
+    let size = match *node {
+        NodeChar(..) | NodeClass(..) | NodeAny => 1,
+        NodeCat(ref nodes) => nodes.iter().fold(0, |acc, node| acc + program_size(node)),
+        NodeAlt(ref nodes) => nodes.iter().fold(0, |acc, node| acc + program_size(node) + 2),
+        NodeRepeat(ref node, min, max) => {
+            let count = cmp::min(max.unwrap_or(min), MAX_PROGRAM_SIZE) + 1;
+            (program_size(&**node) + 1) * count
+        }
+    };
+    cmp::min(size, MAX_PROGRAM_SIZE + 1)
+}
+
+fn compile<'a>(node: &Node<'a>, prog: &mut Vec<Inst<'a>>) {
+    match *node {
+        NodeChar(ch) => { prog.push(InstChar(ch)); }
+        NodeClass(ref class) => { prog.push(InstClass(class.clone())); }
+        NodeAny => { prog.push(InstAny); }
+        NodeCat(ref nodes) => {
+            for node in nodes.iter() {
+                compile(node, prog);
+            }
+        }
+        NodeAlt(ref nodes) => {
+            let mut jumps = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                if i + 1 < nodes.len() {
+                    let split = prog.len();
+                    prog.push(InstSplit(split + 1, 0)); // patched below
+                    compile(node, prog);
+                    jumps.push(prog.len());
+                    prog.push(InstJmp(0)); // patched below
+                    *prog.get_mut(split) = InstSplit(split + 1, prog.len());
+                } else {
+                    compile(node, prog);
+                }
+            }
+            let end = prog.len();
+            for &jump in jumps.iter() {
+                *prog.get_mut(jump) = InstJmp(end);
+            }
+        }
+        NodeRepeat(ref node, min, max) => {
+            for _ in range(0, min) {
+                compile(&**node, prog);
+            }
+            match max {
+                None => {
+                    let split = prog.len();
+                    prog.push(InstSplit(split + 1, 0)); // patched below
+                    compile(&**node, prog);
+                    prog.push(InstJmp(split));
+                    *prog.get_mut(split) = InstSplit(split + 1, prog.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in range(min, max) {
+                        splits.push(prog.len());
+                        prog.push(InstSplit(0, 0)); // patched below
+                        compile(&**node, prog);
+                    }
+                    let end = prog.len();
+                    for &split in splits.iter() {
+                        *prog.get_mut(split) = InstSplit(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Regex<'a> {
+    /// Parses and compiles the regular expression. The offsets in the error are relative
+    /// to `src`.
+    pub fn new(src: &'a str) -> Result<Regex<'a>, FormatError> {
+        let mut parser = Parser { src: src, pos: 0 };
+        let node = try!(parser.parse_alt());
+        if parser.pos < src.len() { // only possible with the unmatched `)`
+            return Err(FormatError { msg: ~"unexpected `)` in the regular expression",
+                                     start: parser.pos, end: parser.pos + 1 });
+        }
+        if program_size(&node) > MAX_PROGRAM_SIZE {
+            return Err(FormatError { msg: ~"the regular expression is too large",
+                                     start: 0, end: src.len() });
+        }
+
+        let mut prog = Vec::new();
+        compile(&node, &mut prog);
+        prog.push(InstMatch);
+        Ok(Regex { src: src, prog: prog })
+    }
+}
+
+impl<'a> Pattern for Regex<'a> {
+    /// Returns the length of the first match in the backtracking order, where the
+    /// repetitions are greedy and the alternatives are tried from the left.
+    fn match_prefix(&self,
+                    peek: |uint| -> IoResult<Option<(char, uint)>>) -> IoResult<Option<uint>> {
+        // the same thread at the same position will fail again, so it is tried only once.
+        // this also prevents the infinite loop with the empty repetition like `(a*)*`.
+        let mut visited = HashSet::new();
+        let mut threads = vec!((0u, 0u));
+        loop {
+            let (mut pc, mut pos) = match threads.pop() {
+                Some(thread) => thread,
+                None => { return Ok(None); }
+            };
+            loop {
+                if !visited.insert((pc, pos)) { break; }
+                match *self.prog.get(pc) {
+                    InstChar(expected) => match try!(peek(pos)) {
+                        Some((ch, width)) if ch == expected => { pc += 1; pos += width; }
+                        _ => { break; }
+                    },
+                    InstClass(ref class) => match try!(peek(pos)) {
+                        Some((ch, width)) if class.contains(ch) => { pc += 1; pos += width; }
+                        _ => { break; }
+                    },
+                    InstAny => match try!(peek(pos)) {
+                        Some((ch, width)) if ch != '\n' => { pc += 1; pos += width; }
+                        _ => { break; }
+                    },
+                    InstSplit(first, second) => {
+                        threads.push((second, pos));
+                        pc = first;
+                    }
+                    InstJmp(target) => { pc = target; }
+                    InstMatch => { return Ok(Some(pos)); }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rt::Pattern;
+
+    fn match_str(re: &str, s: &str) -> Option<uint> {
+        let re = Regex::new(re).unwrap();
+        re.match_prefix(|offset| {
+            if offset < s.len() {
+                let ch = s.char_at(offset);
+                Ok(Some((ch, ch.len_utf8_bytes())))
+            } else {
+                Ok(None)
+            }
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(match_str("abc", "abcd"), Some(3));
+        assert_eq!(match_str("abc", "abd"), None);
+        assert_eq!(match_str("", "abc"), Some(0));
+        assert_eq!(match_str("a*", "aaab"), Some(3));
+        assert_eq!(match_str("a+", "baa"), None);
+        assert_eq!(match_str("ab?c", "ac"), Some(2));
+        assert_eq!(match_str("a{2,3}", "aaaa"), Some(3));
+        assert_eq!(match_str("a{2,3}", "a"), None);
+        assert_eq!(match_str("a{2,}", "aaaa"), Some(4));
+        assert_eq!(match_str("a{2}", "aaaa"), Some(2));
+        assert_eq!(match_str("x|xy|xyz", "xyz"), Some(1));
+        assert_eq!(match_str("(x|xy)z", "xyz"), Some(3));
+        assert_eq!(match_str("[0-9]+(\\.[0-9]+)*", "1.20.3-beta"), Some(6));
+        assert_eq!(match_str("[0-9]+(\\.[0-9]+)*", "1.x"), Some(1));
+        assert_eq!(match_str("\\w+", "foo_1 bar"), Some(5));
+        assert_eq!(match_str("\\S+", "안녕 하세요"), Some(6));
+        assert_eq!(match_str(".*", "ab\ncd"), Some(2));
+        assert_eq!(match_str("\\p{Lu}\\pL*", "Foo bar"), Some(3));
+        assert_eq!(match_str("\\P{N}+", "ab12"), Some(2));
+        assert_eq!(match_str("(a*)*b", "aab"), Some(3));
+        assert_eq!(match_str("(a*)*b", "aac"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Regex::new("(a").is_err());
+        assert!(Regex::new("a)").is_err());
+        assert!(Regex::new("[a").is_err());
+        assert!(Regex::new("*a").is_err());
+        assert!(Regex::new("a**").is_err());
+        assert!(Regex::new("a{3,2}").is_err());
+        assert!(Regex::new("a{x}").is_err());
+        assert!(Regex::new("a\\").is_err());
+        assert!(Regex::new("\\p{Foo}").is_err());
+        assert!(Regex::new("\\p{L").is_err());
+        assert!(Regex::new("((a{100}){100}){100}").is_err());
+        assert_eq!(Regex::new("ab(c").unwrap_err().start, 2);
+    }
+}
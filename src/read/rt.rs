@@ -1,25 +1,497 @@
-use std::io::{IoResult, standard_error, InvalidInput};
-use buffer::LookaheadBuffer;
-pub use parse::{Flags, FlagSignPlus, FlagSignMinus, FlagAlternate};
+use std::{char, cmp, str, uint};
+use std::io::{IoError, IoResult, EndOfFile, standard_error};
+use buffer::{LookaheadBuffer, Mark};
+use width;
+pub use parse::{Flags, FlagSignPlus, FlagSignMinus, FlagAlternate, FlagFixedWidth};
 pub use parse::{Alignment, AlignLeft, AlignRight, AlignCenter, AlignUnknown};
+pub use parse::CharClass;
+
+/// The kind of `ScanError`.
+#[deriving(Eq,Clone,Show)]
+pub enum ScanErrorKind {
+    IoFailure,       // the underlying reader has failed; see `ScanError::cause`
+    UnexpectedEof,   // the input has ended before the format does
+    LiteralMismatch, // the input does not match the literal or whitespace in the format
+    NoValue,         // the input has no value for the argument, e.g. the pattern not matching
+    InvalidNumber,   // the input has no number for the numeric argument
+    Overflow,        // the number does not fit in the type
+    InvalidUtf8,     // the input is not a valid UTF-8 string
+    TrailingInput,   // the line has characters after the format in the line mode,
+                     // or the fixed-width field has characters after the value
+    InvalidFormat,   // the format is invalid, only detected at runtime by `interp`
+    NotBorrowable,   // `&str` is scanned from `Buffer` rather than from the string
+}
+
+/// The error from scanning, with the position in the input.
+#[deriving(Eq,Clone,Show)]
+pub struct ScanError {
+    kind: ScanErrorKind,
+    name: Option<~str>,     // the argument being scanned, if any
+    offset: uint,           // the number of bytes consumed before the error
+    snippet: ~str,          // the input at the offset, up to `SNIPPET_LEN` bytes
+    piece: Option<uint>,    // the index of the top-level piece in the format being matched
+    assigned: Vec<~str>,    // the arguments successfully scanned before, in order
+    skipped: Vec<u8>,       // the input skipped by `Records` to recover from the error
+    cause: Option<IoError>, // the original error for `IoFailure`
+}
+
+/// The maximum length of `ScanError::snippet` in bytes.
+pub static SNIPPET_LEN: uint = 32;
+
+impl ScanError {
+    /// Creates an error without the position. `Scan` functions fill the position
+    /// for the errors from the traits.
+    pub fn new(kind: ScanErrorKind) -> ScanError {
+        ScanError { kind: kind, name: None, offset: 0, snippet: ~"", piece: None,
+                    assigned: Vec::new(), skipped: Vec::new(), cause: None }
+    }
+
+    /// Converts the I/O error without the position. The end of file is `UnexpectedEof`.
+    pub fn from_io(err: IoError) -> ScanError {
+        if err.kind == EndOfFile {
+            ScanError::new(UnexpectedEof)
+        } else {
+            ScanError { cause: Some(err), ..ScanError::new(IoFailure) }
+        }
+    }
+}
+
+pub type ScanResult<T> = Result<T, ScanError>;
+
+// converts the I/O error to `ScanError` without the position
+macro_rules! try_io(
+    ($e:expr) => (match $e { Ok(v) => v, Err(err) => return Err(ScanError::from_io(err)) })
+)
 
 pub struct Scanner<'a> {
+    name: Option<&'static str>, // the argument being scanned, for errors
+    piece: Option<uint>, // the top-level piece being matched, for errors
+    assigned: Vec<&'static str>, // the arguments scanned so far, for errors
     flags: uint, // packed
     fill: Option<char>, // None for every whitespace
     align: Alignment,
-    width: Option<uint>,
+    width: Option<uint>, // the maximum number of characters in the value, excluding the padding
 
     buf: LookaheadBuffer<'a>,
 }
 
+/// A position marked by `Scanner::mark`.
+pub struct ScanMark {
+    priv mark: Mark,
+    priv progress: Progress,
+}
+
+// the part of the scanner state reported in errors
+struct Progress {
+    name: Option<&'static str>,
+    piece: Option<uint>,
+    assigned: uint, // the length of `Scanner::assigned`
+}
+
 impl<'a> Scanner<'a> {
+    pub fn new(buf: &'a mut Buffer) -> Scanner<'a> {
+        Scanner { name: None, piece: None, assigned: Vec::new(),
+                  flags: 0, fill: None, align: AlignUnknown, width: None,
+                  buf: LookaheadBuffer::new(buf) }
+    }
+
+    /// Creates a scanner reading directly from the string. Only this scanner can
+    /// scan borrowed strings (`&'a str`) without copying.
+    pub fn from_str(s: &'a str) -> Scanner<'a> {
+        Scanner { name: None, piece: None, assigned: Vec::new(),
+                  flags: 0, fill: None, align: AlignUnknown, width: None,
+                  buf: LookaheadBuffer::from_slice(s.as_bytes()) }
+    }
+
+    /// Returns the number of bytes consumed so far. Called by the `lex_str!` expansion.
+    pub fn consumed(&self) -> uint {
+        self.buf.consumed()
+    }
+
+    /// Reads a line to be scanned in the line mode of `lex_iter!` and of `lex!` with `in`,
+    /// after any lookahead.
+    pub fn read_line(&mut self) -> IoResult<~str> {
+        self.buf.read_line()
+    }
+
+    /// Returns true if there is no more input. Errors other than `EndOfFile` are returned.
+    pub fn is_eof(&mut self) -> IoResult<bool> {
+        match self.buf.peek_byte() {
+            Ok(b) => Ok(b.is_none()),
+            Err(ref err) if err.kind == EndOfFile => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Creates an error at the current position for the current argument.
+    pub fn error(&mut self, kind: ScanErrorKind) -> ScanError {
+        self.locate(ScanError::new(kind))
+    }
+
+    /// Fills the position of the error with the current one, and the name with
+    /// the current argument unless the error already has one.
+    pub fn locate(&mut self, err: ScanError) -> ScanError {
+        let name = match err.name {
+            Some(name) => Some(name),
+            None => self.name.map(|name| name.to_owned()),
+        };
+        ScanError { name: name, offset: self.consumed(), snippet: self.snippet(),
+                    piece: self.piece, assigned: self.assigned_names(), ..err }
+    }
+
+    /// Returns the names of the arguments successfully scanned so far, in order.
+    pub fn assigned_names(&self) -> Vec<~str> {
+        self.assigned.iter().map(|name| name.to_owned()).collect()
+    }
+
+    // returns the input at the current position up to `SNIPPET_LEN` bytes,
+    // without the incomplete or invalid UTF-8 sequence at the end
+    fn snippet(&mut self) -> ~str {
+        let buf = match self.buf.fill_request(SNIPPET_LEN) {
+            Ok(buf) => buf,
+            Err(_) => { return ~""; }
+        };
+        let mut len = cmp::min(buf.len(), SNIPPET_LEN);
+        loop {
+            match str::from_utf8(buf.slice_to(len)) {
+                Some(s) => { return s.to_owned(); }
+                None => { len -= 1; }
+            }
+        }
+    }
+
+    // returns the error for the argument without a value, which is `UnexpectedEof`
+    // at the end of file and `kind` otherwise
+    fn missing(&mut self, kind: ScanErrorKind) -> ScanError {
+        let kind = if self.at_eof() {UnexpectedEof} else {kind};
+        self.error(kind)
+    }
+
+    /// Same as `locate` but not for the argument, as for a literal in the format.
+    pub fn locate_literal(&mut self, err: ScanError) -> ScanError {
+        let name = self.name.take();
+        let err = self.locate(err);
+        self.name = name;
+        err
+    }
+
+    /// Sets the index of the top-level piece to be matched. Called by the `lex!` expansion.
+    pub fn set_piece(&mut self, piece: uint) {
+        self.piece = Some(piece);
+    }
+
+    // records the current argument as successfully scanned
+    fn assign(&mut self) {
+        match self.name {
+            Some(name) => { self.assigned.push(name); }
+            None => {}
+        }
+    }
+
+    // saves the progress, which the nested scans (e.g. from `#[read]`) may change
+    fn save_progress(&self) -> Progress {
+        Progress { name: self.name, piece: self.piece, assigned: self.assigned.len() }
+    }
+
+    fn restore_progress(&mut self, progress: Progress) {
+        self.name = progress.name;
+        self.piece = progress.piece;
+        self.assigned.truncate(progress.assigned);
+    }
+
+    /// Sets the scan spec for the next argument. Called by the `lex!` expansion.
+    pub fn set_spec(&mut self, name: Option<&'static str>, flags: uint, fill: Option<char>,
+                    align: Alignment, width: Option<uint>) {
+        self.name = name;
+        self.flags = flags;
+        self.fill = fill;
+        self.align = align;
+        self.width = width;
+    }
+
+    /// Marks the current position. See `LookaheadBuffer::mark`.
+    pub fn mark(&mut self) -> ScanMark {
+        ScanMark { mark: self.buf.mark(), progress: self.save_progress() }
+    }
+
+    /// Rewinds to the marked position and releases the mark. The arguments scanned
+    /// after the mark are no longer reported as assigned.
+    pub fn rewind(&mut self, mark: ScanMark) {
+        self.restore_progress(mark.progress);
+        self.buf.rewind(mark.mark)
+    }
+
+    /// Releases the mark without rewinding.
+    pub fn release(&mut self, mark: ScanMark) {
+        self.buf.release(mark.mark)
+    }
+
+    /// Skips any amount of whitespace, including none.
+    pub fn skip_whitespace(&mut self) -> ScanResult<uint> {
+        let ret = self.buf.read_pad_byte_if(|ch| ch == ' ' as u8 || ch == '\t' as u8 ||
+                                                 ch == '\r' as u8 || ch == '\n' as u8);
+        match ret {
+            Ok(n) => Ok(n),
+            Err(ref err) if err.kind == EndOfFile => Ok(0),
+            Err(err) => Err(self.locate_literal(ScanError::from_io(err))),
+        }
+    }
+
+    /// Skips at least one whitespace, or fails with `LiteralMismatch` (`UnexpectedEof` at
+    /// the end) if there is none.
+    pub fn expect_whitespace(&mut self) -> ScanResult<()> {
+        if try!(self.skip_whitespace()) == 0 {
+            let kind = if self.at_eof() {UnexpectedEof} else {LiteralMismatch};
+            return Err(self.locate_literal(ScanError::new(kind)));
+        }
+        Ok(())
+    }
+
+    // same as `is_eof` but treats any error as not at the end
+    fn at_eof(&mut self) -> bool {
+        match self.is_eof() { Ok(eof) => eof, Err(_) => false }
+    }
+
+    /// Consumes `lit` if the input starts with it, or fails with `LiteralMismatch`
+    /// (`UnexpectedEof` if the input ends before `lit` does) otherwise.
+    /// Nothing is consumed on the failure.
+    pub fn expect_str(&mut self, lit: &str) -> ScanResult<()> {
+        let len = lit.len();
+        let ret = match self.buf.fill_request(len) {
+            Ok(buf) => {
+                let avail = cmp::min(buf.len(), len);
+                if buf.slice_to(avail) != lit.as_bytes().slice_to(avail) {
+                    Err(ScanError::new(LiteralMismatch))
+                } else if avail < len {
+                    Err(ScanError::new(UnexpectedEof))
+                } else {
+                    Ok(())
+                }
+            }
+            Err(err) => Err(ScanError::from_io(err)),
+        };
+        match ret {
+            Ok(()) => { self.buf.consume(len); Ok(()) }
+            Err(err) => Err(self.locate_literal(err)),
+        }
+    }
+
+    /// Same as `expect_str` but compares each character after the simple Unicode case
+    /// folding. Multi-character foldings (e.g. `ß` to `ss`) are not recognized.
+    pub fn expect_str_ignore_case(&mut self, lit: &str) -> ScanResult<()> {
+        fn fold_case(ch: char) -> char {
+            char::to_lowercase(char::to_uppercase(ch))
+        }
+
+        let mut offset = 0;
+        for expected in lit.chars() {
+            let ch = match self.buf.peek_char_at(offset) {
+                Ok(Some((ch, width))) => { offset += width; ch }
+                Ok(None) => {
+                    // either the end of file or an invalid UTF-8 sequence
+                    let kind = match self.buf.fill_request(offset + 1) {
+                        Ok(buf) if buf.len() > offset => LiteralMismatch,
+                        _ => UnexpectedEof,
+                    };
+                    return Err(self.locate_literal(ScanError::new(kind)));
+                }
+                Err(err) => { return Err(self.locate_literal(ScanError::from_io(err))); }
+            };
+            if fold_case(ch) != fold_case(expected) {
+                return Err(self.locate_literal(ScanError::new(LiteralMismatch)));
+            }
+        }
+        self.buf.consume(offset);
+        Ok(())
+    }
+
+    /// Scans values with `f` repeatedly until `max` values are read or no more value can
+    /// be read, and fails if less than `min` values are read. Values are separated by
+    /// the literal `sep` or any amount of whitespace if `None`. Any input after the last
+    /// value, including the separator, is left unconsumed.
+    pub fn scan_repeat<T>(&mut self, min: uint, max: Option<uint>, sep: Option<&str>,
+                          ignore_case: bool,
+                          f: |&mut Scanner<'a>| -> ScanResult<T>) -> ScanResult<Vec<T>> {
+        // the whole repetition is assigned once
+        let start = self.save_progress();
+        let mut values = Vec::new();
+        loop {
+            if max.map_or(false, |max| values.len() >= max) { break; }
+
+            let mark = self.mark();
+            if !values.is_empty() {
+                let ret = match sep {
+                    Some(sep) if ignore_case => self.expect_str_ignore_case(sep),
+                    Some(sep) => self.expect_str(sep),
+                    None => self.skip_whitespace().map(|_| ()),
+                };
+                match ret {
+                    Ok(()) => {}
+                    Err(err) => {
+                        self.rewind(mark);
+                        if is_mismatch(&err) { break; }
+                        return Err(err);
+                    }
+                }
+            }
+
+            match f(self) {
+                Ok(v) => {
+                    // stop at the empty value, which would be otherwise repeated forever
+                    let progress = self.buf.consumed_since(&mark.mark) > 0;
+                    self.release(mark);
+                    self.assigned.truncate(start.assigned);
+                    values.push(v);
+                    if !progress { break; }
+                }
+                Err(err) => {
+                    self.rewind(mark);
+                    if is_mismatch(&err) { break; }
+                    return Err(err);
+                }
+            }
+        }
+
+        self.restore_progress(start);
+        if values.len() < min {
+            return Err(self.error(NoValue));
+        }
+        self.assign();
+        Ok(values)
+    }
+
+    // consumes the input up to and including the next newline, or to the end of file,
+    // and returns the consumed bytes
+    fn skip_line(&mut self) -> IoResult<Vec<u8>> {
+        let mut skipped = Vec::new();
+        loop {
+            let (len, done) = {
+                let buf = match self.buf.fill_request(1) {
+                    Ok(buf) => buf,
+                    Err(ref err) if err.kind == EndOfFile => { break; }
+                    Err(err) => { return Err(err); }
+                };
+                let (len, done) = match buf.iter().position(|&ch| ch == '\n' as u8) {
+                    Some(i) => (i + 1, true),
+                    None => (buf.len(), false),
+                };
+                skipped.push_all(buf.slice_to(len));
+                (len, done)
+            };
+            self.buf.consume(len);
+            if done { break; }
+        }
+        Ok(skipped)
+    }
+
+    // consumes the input up to the next occurrence of `lit`, which is not consumed,
+    // or to the end of file, and returns the consumed bytes. `lit` at the current position
+    // is ignored, so at least one byte is consumed unless at the end of file.
+    fn skip_to(&mut self, lit: &str) -> IoResult<Vec<u8>> {
+        let lit = lit.as_bytes();
+        let mut skipped = Vec::new();
+        loop {
+            let (len, done) = {
+                let buf = match self.buf.fill_request(lit.len() + 1) {
+                    Ok(buf) => buf,
+                    Err(ref err) if err.kind == EndOfFile => { break; }
+                    Err(err) => { return Err(err); }
+                };
+                let start = if skipped.is_empty() {1} else {0};
+                let end = if buf.len() >= lit.len() {buf.len() - lit.len() + 1} else {0};
+                let found = range(start, cmp::max(start, end)).find(|&i| {
+                    buf.slice_from(i).starts_with(lit)
+                });
+                let (len, done) = match found {
+                    Some(i) => (i, true),
+                    // a short buffer is only returned at the end of file
+                    None if buf.len() <= lit.len() => (buf.len(), true),
+                    // the last bytes may start `lit` continuing in the next buffer
+                    None => (end, false),
+                };
+                skipped.push_all(buf.slice_to(len));
+                (len, done)
+            };
+            self.buf.consume(len);
+            if done { break; }
+        }
+        Ok(skipped)
+    }
+
+    // returns the maximum number of characters in the value; the zero width has no limit,
+    // and the fixed-width field is limited by `in_field` instead
+    fn max_chars(&self) -> uint {
+        match self.width {
+            Some(width) if width > 0 && !self.fixed_width() => width,
+            _ => uint::MAX,
+        }
+    }
+
+    // returns true if the argument is a fixed-width field (the `c` modifier of `lex!`)
+    fn fixed_width(&self) -> bool {
+        ((self.flags >> FlagFixedWidth as uint) & 1) == 1
+    }
+
+    // returns the length in bytes of the next `width` columns, up to the newline or
+    // the end of file. A wide character crossing the last column is left out.
+    fn field_len(&mut self, width: uint) -> ScanResult<uint> {
+        let mut columns = 0;
+        let mut len = 0;
+        while columns < width {
+            let (ch, chlen) = match self.buf.peek_char_at(len) {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    // either the end of file or an invalid UTF-8 sequence
+                    let invalid = match self.buf.fill_request(len + 1) {
+                        Ok(buf) => buf.len() > len,
+                        Err(_) => false,
+                    };
+                    if invalid { return Err(ScanError::new(InvalidUtf8)); }
+                    break;
+                }
+                Err(ref err) if err.kind == EndOfFile => { break; }
+                Err(err) => { return Err(ScanError::from_io(err)); }
+            };
+            if ch == '\n' || ch == '\r' { break; }
+            let chwidth = width::char_width(ch);
+            if columns + chwidth > width { break; }
+            columns += chwidth;
+            len += chlen;
+        }
+        Ok(len)
+    }
+
+    // calls `f` as is, or within the field if the argument is a fixed-width field.
+    // the field spans `width` columns (see `width::char_width`) up to the newline, and
+    // the input appears to end there; the scanners skip the fill on the side implied by
+    // the alignment as usual. returns the result and whether the whole field is consumed.
+    fn in_field<R>(&mut self, f: |&mut Scanner<'a>| -> ScanResult<R>) -> ScanResult<(R, bool)> {
+        let width = match self.width {
+            Some(width) if width > 0 && self.fixed_width() => width,
+            _ => { return Ok((try!(f(self)), true)); }
+        };
+        let end = self.consumed() + try!(self.field_len(width));
+        let outer = self.buf.limit();
+        self.buf.set_limit(Some(outer.map_or(end, |outer| cmp::min(outer, end))));
+        let ret = f(self);
+        self.buf.set_limit(outer);
+        let ret = try!(ret);
+        Ok((ret, self.consumed() >= end))
+    }
+
+    // the padding may run to the end of file (or the end of the field), which is not an error.
+    // the count is lost then, but no caller uses it.
     fn skip_pad(&mut self) -> IoResult<uint> {
-        match self.fill {
+        let ret = match self.fill {
             Some(ch) => self.buf.read_pad_char(ch),
             None => self.buf.read_pad_byte_if(|ch| ch == ' ' as u8 ||
                                                    ch == '\t' as u8 ||
                                                    ch == '\r' as u8 ||
                                                    ch == '\n' as u8),
+        };
+        match ret {
+            Err(ref err) if err.kind == EndOfFile => Ok(0),
+            ret => ret,
         }
     }
 
@@ -38,99 +510,389 @@ impl<'a> Scanner<'a> {
     }
 
     pub fn trim_postpad<'a>(&self, buf: &'a str) -> &'a str {
-        match self.align {
-            AlignRight | AlignCenter => match self.fill {
-                Some(ch) => buf.trim_right_chars(&ch),
-                None => buf.trim_right(),
-            },
-            _ => buf
+        trim_postpad(self.align, self.fill, buf)
+    }
+}
+
+fn trim_postpad<'a>(align: Alignment, fill: Option<char>, buf: &'a str) -> &'a str {
+    match align {
+        AlignRight | AlignCenter => match fill {
+            Some(ch) => buf.trim_right_chars(&ch),
+            None => buf.trim_right(),
+        },
+        _ => buf
+    }
+}
+
+// same as `trim_postpad` but for the bytes, which need not be valid UTF-8
+fn trim_postpad_bytes<'a>(align: Alignment, fill: Option<char>, buf: &'a [u8]) -> &'a [u8] {
+    let mut buf = buf;
+    match (align, fill) {
+        (AlignRight, Some(ch)) | (AlignCenter, Some(ch)) => {
+            let mut enc = [0u8, ..4];
+            let n = ch.encode_utf8(enc);
+            while buf.ends_with(enc.slice_to(n)) { buf = buf.slice_to(buf.len() - n); }
+        }
+        (AlignRight, None) | (AlignCenter, None) => {
+            while buf.len() > 0 && is_ascii_whitespace(buf[buf.len() - 1]) {
+                buf = buf.slice_to(buf.len() - 1);
+            }
         }
+        _ => {}
     }
+    buf
+}
+
+fn is_ascii_whitespace(b: u8) -> bool {
+    b < 0x80 && char::is_whitespace(b as char)
 }
 
-pub trait Read<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+macro_rules! define_traits {
+    ($($Trait:ident;)*) => {
+        $(
+            pub trait $Trait<'a> {
+                /// Scans a value, or returns `None` if the input has no value.
+                /// The errors have no position, which `Scan` functions fill.
+                fn scan(s: &mut Scanner<'a>) -> ScanResult<Option<Self>>;
+
+                /// Scans a value into the existing place, which may reuse its storage.
+                /// Returns `false` and leaves the place unchanged if there is no value.
+                fn scan_into(&mut self, s: &mut Scanner<'a>) -> ScanResult<bool> {
+                    match try!($Trait::scan(s)) {
+                        Some(v) => { *self = v; Ok(true) }
+                        None => Ok(false)
+                    }
+                }
+            }
+        )*
+    }
 }
 
-pub trait Integer<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+define_traits! {
+    Read;
+    Integer;
+    Signed;
+    Unsigned;
+    Char;
+    Octal;
+    Hex;
+    String;
+    Binary;
+    Float;
+    Exp;
+    Bytes;
 }
 
-pub trait Signed<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+// XXX these should really be `Trait::<for T>::scan(s)` once it gets supported
+macro_rules! define_function_aliases {
+    ($($name:ident, $into:ident for $Trait:ident => $missing:ident;)*) => {
+        pub struct Scan;
+        impl<'a> Scan {
+            $(
+                pub fn $name<T:$Trait<'a>>(s: &mut Scanner<'a>) -> ScanResult<T> {
+                    let progress = s.save_progress();
+                    let ret = s.in_field(|s| $Trait::scan(s));
+                    s.restore_progress(progress);
+                    match ret {
+                        Ok((Some(v), true)) => { s.assign(); Ok(v) }
+                        Ok((Some(_), false)) => Err(s.error(TrailingInput)),
+                        Ok((None, _)) => Err(s.missing($missing)),
+                        Err(err) => Err(s.locate(err)),
+                    }
+                }
+
+                pub fn $into<T:$Trait<'a>>(s: &mut Scanner<'a>, place: &mut T) -> ScanResult<()> {
+                    let progress = s.save_progress();
+                    let ret = s.in_field(|s| place.scan_into(s));
+                    s.restore_progress(progress);
+                    match ret {
+                        Ok((true, true)) => { s.assign(); Ok(()) }
+                        Ok((true, false)) => Err(s.error(TrailingInput)),
+                        Ok((false, _)) => Err(s.missing($missing)),
+                        Err(err) => Err(s.locate(err)),
+                    }
+                }
+            )*
+        }
+    }
+}
+
+// the error kinds are used when the input has no value but is not at the end
+define_function_aliases! {
+    for_read,     into_read     for Read     => NoValue;
+    for_integer,  into_integer  for Integer  => InvalidNumber;
+    for_signed,   into_signed   for Signed   => InvalidNumber;
+    for_unsigned, into_unsigned for Unsigned => InvalidNumber;
+    for_char,     into_char     for Char     => NoValue;
+    for_octal,    into_octal    for Octal    => InvalidNumber;
+    for_hex,      into_hex      for Hex      => InvalidNumber;
+    for_string,   into_string   for String   => NoValue;
+    for_binary,   into_binary   for Binary   => InvalidNumber;
+    for_float,    into_float    for Float    => InvalidNumber;
+    for_exp,      into_exp      for Exp      => InvalidNumber;
+    for_bytes,    into_bytes    for Bytes    => NoValue;
 }
 
-pub trait Unsigned<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+/// Patterns which can match a prefix of the input (`{x:[...]}` and `{x:/.../}`).
+pub trait Pattern {
+    /// Returns the length of the matched prefix in bytes, or `None` if not matched.
+    /// `peek` returns the character and its length at given offset, or `None` at the end.
+    fn match_prefix(&self,
+                    peek: |uint| -> IoResult<Option<(char, uint)>>) -> IoResult<Option<uint>>;
 }
 
-pub trait Char<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+impl<'a> Pattern for CharClass<'a> {
+    /// Matches the longest non-empty run of characters in the class.
+    fn match_prefix(&self,
+                    peek: |uint| -> IoResult<Option<(char, uint)>>) -> IoResult<Option<uint>> {
+        let mut i = 0;
+        loop {
+            match try!(peek(i)) {
+                Some((ch, width)) if self.contains(ch) => { i += width; }
+                _ => { break; }
+            }
+        }
+        Ok(if i > 0 {Some(i)} else {None})
+    }
 }
 
-pub trait Octal<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+/// Types which can be made from the input matched by the pattern.
+pub trait Capture<'a> {
+    fn capture(s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<Option<Self>>;
+
+    /// Same as `Read::scan_into` but captures the input matched by the pattern.
+    fn capture_into(&mut self, s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<bool> {
+        match try!(Capture::capture(s, pat)) {
+            Some(v) => { *self = v; Ok(true) }
+            None => Ok(false)
+        }
+    }
 }
 
-pub trait Hex<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+impl<'a> Scan {
+    pub fn for_capture<T:Capture<'a>>(s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<T> {
+        let progress = s.save_progress();
+        let ret = s.in_field(|s| Capture::capture(s, pat));
+        s.restore_progress(progress);
+        match ret {
+            Ok((Some(v), true)) => { s.assign(); Ok(v) }
+            Ok((Some(_), false)) => Err(s.error(TrailingInput)),
+            Ok((None, _)) => Err(s.missing(NoValue)),
+            Err(err) => Err(s.locate(err)),
+        }
+    }
+
+    pub fn into_capture<T:Capture<'a>>(s: &mut Scanner<'a>, pat: &Pattern,
+                                       place: &mut T) -> ScanResult<()> {
+        let progress = s.save_progress();
+        let ret = s.in_field(|s| place.capture_into(s, pat));
+        s.restore_progress(progress);
+        match ret {
+            Ok((true, true)) => { s.assign(); Ok(()) }
+            Ok((true, false)) => Err(s.error(TrailingInput)),
+            Ok((false, _)) => Err(s.missing(NoValue)),
+            Err(err) => Err(s.locate(err)),
+        }
+    }
 }
 
-pub trait String<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+/// Returns true if the error is caused by the input not matching the format,
+/// so that the caller may rewind and try to match the input differently.
+pub fn is_mismatch(err: &ScanError) -> bool {
+    err.kind != IoFailure && err.kind != InvalidFormat && err.kind != NotBorrowable
 }
 
-pub trait Binary<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+/// Converts the result of `lex!` in the out-parameter mode to the number of assigned
+/// arguments, like C `scanf`. A mismatch merely stops the scanning, but an end of file
+/// before the first assignment and other I/O errors are returned as errors.
+pub fn assigned_count(result: ScanResult<()>, count: uint) -> ScanResult<uint> {
+    match result {
+        Ok(()) => Ok(count),
+        Err(err) => {
+            if is_mismatch(&err) && (err.kind != UnexpectedEof || count > 0) {
+                Ok(count)
+            } else {
+                Err(err)
+            }
+        }
+    }
 }
 
-pub trait Float<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+/// Reads a line to be scanned in the line mode of `lex!`.
+pub fn read_line(buf: &mut Buffer) -> IoResult<~str> {
+    buf.read_line()
 }
 
-pub trait Exp<'a> {
-    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<Self>>;
+/// Returns the first line of the string including the line ending, to be scanned in
+/// the line mode of `lex_str!`. Fails with `EndOfFile` for the empty string like `read_line`.
+pub fn first_line<'a>(s: &'a str) -> IoResult<&'a str> {
+    if s.is_empty() { return Err(standard_error(EndOfFile)); }
+    match s.find('\n') {
+        Some(i) => Ok(s.slice_to(i + 1)),
+        None => Ok(s),
+    }
 }
 
-// XXX these should really be `Trait::<for T>::scan(s)` once it gets supported
-macro_rules! define_function_aliases {
-    ($($name:ident for $Trait:ident;)*) => {
-        pub struct Scan;
-        impl<'a> Scan {
-            $(
-                pub fn $name<T:$Trait<'a>>(s: &'a mut Scanner<'a>) -> IoResult<T> {
-                    match try!($Trait::scan(s)) {
-                        Some(v) => Ok(v),
-                        None => Err(standard_error(InvalidInput))
-                    }
-                }
-            )*
+/// Checks if the successful scan has consumed the whole line except for the line ending
+/// (`\n` or `\r\n`), and fails with `TrailingInput` having the leftover otherwise.
+pub fn check_line<T>(result: ScanResult<T>, s: &Scanner, line: &str) -> ScanResult<T> {
+    if result.is_err() { return result; }
+
+    let mut rest = line.slice_from(s.consumed());
+    if rest.ends_with("\n") {
+        rest = rest.slice_to(rest.len() - 1);
+        if rest.ends_with("\r") {
+            rest = rest.slice_to(rest.len() - 1);
+        }
+    }
+    if rest.is_empty() {
+        result
+    } else {
+        let mut snippet = rest;
+        if snippet.len() > SNIPPET_LEN {
+            let mut end = SNIPPET_LEN;
+            while !snippet.is_char_boundary(end) { end -= 1; }
+            snippet = snippet.slice_to(end);
         }
+        Err(ScanError { offset: s.consumed(), snippet: snippet.to_owned(),
+                        assigned: s.assigned_names(), ..ScanError::new(TrailingInput) })
     }
 }
 
-define_function_aliases! {
-    for_read     for Read;
-    for_integer  for Integer;
-    for_signed   for Signed;
-    for_unsigned for Unsigned;
-    for_char     for Char;
-    for_octal    for Octal;
-    for_hex      for Hex;
-    for_string   for String;
-    for_binary   for Binary;
-    for_float    for Float;
-    for_exp      for Exp;
+/// Scans the whole string as a value of the type, or returns `None` if the string does not
+/// match or has a leftover. Used for `FromStr` implemented by `#[read]`.
+pub fn read_from_str<'a, T: Read<'a>>(s: &'a str) -> Option<T> {
+    let mut scanner = Scanner::from_str(s);
+    match Read::scan(&mut scanner) {
+        Ok(Some(value)) if scanner.consumed() == s.len() => Some(value),
+        _ => None,
+    }
+}
+
+/// What `Records` does after a record fails to match.
+#[deriving(Eq,Clone,Show)]
+pub enum Recovery {
+    Stop,         // ends the iteration after the error
+    SkipLine,     // skips the record to the next line
+    SkipTo(~str), // skips the record to the next occurrence of the literal
+}
+
+/// An iterator scanning the same format repeatedly, returned by `lex_iter!`.
+/// The scanner is kept across records, so no lookahead is lost between them.
+/// The iteration ends at the end of file between records, which may follow whitespace
+/// like the final newline, or after the first error unless the recovery policy is set;
+/// the end of file in the middle of a record is an error.
+pub struct Records<'a, 'b, C, T> {
+    priv scanner: Scanner<'a>,
+    priv context: C,
+    priv f: 'b |&mut Scanner<'a>, &C| -> ScanResult<T>,
+    priv recovery: Recovery,
+    priv done: bool,
+}
+
+impl<'a, 'b, C, T> Records<'a, 'b, C, T> {
+    /// Creates an iterator calling `f` for every record. `context` is kept across records
+    /// and passed to `f`, so that `lex_iter!` builds its patterns only once.
+    pub fn new(scanner: Scanner<'a>, context: C, f: 'b |&mut Scanner<'a>, &C| -> ScanResult<T>)
+               -> Records<'a, 'b, C, T> {
+        Records { scanner: scanner, context: context, f: f, recovery: Stop, done: false }
+    }
+
+    /// Sets the recovery policy. Other than `Stop`, the failed record is skipped from its
+    /// start and the iteration continues; the error has the skipped bytes in
+    /// `ScanError::skipped`, so that the record can be reported as is. The iteration
+    /// still ends after I/O errors.
+    pub fn recover(self, recovery: Recovery) -> Records<'a, 'b, C, T> {
+        Records { recovery: recovery, ..self }
+    }
+}
+
+impl<'a, 'b, C, T> Iterator<ScanResult<T>> for Records<'a, 'b, C, T> {
+    fn next(&mut self) -> Option<ScanResult<T>> {
+        if self.done { return None; }
+
+        // the whitespace is left to the record unless it is trailing
+        let mark = self.scanner.mark();
+        let eof = match self.scanner.skip_whitespace() {
+            Ok(_) => match self.scanner.is_eof() {
+                Ok(eof) => Ok(eof),
+                Err(err) => Err(self.scanner.locate(ScanError::from_io(err))),
+            },
+            Err(err) => Err(err),
+        };
+        match eof {
+            Ok(false) => { self.scanner.rewind(mark); }
+            Ok(true) => {
+                self.scanner.release(mark);
+                self.done = true;
+                return None;
+            }
+            Err(err) => {
+                self.scanner.release(mark);
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+
+        self.scanner.piece = None;
+        self.scanner.assigned.clear();
+        let mark = self.scanner.mark();
+        let err = match (self.f)(&mut self.scanner, &self.context) {
+            Ok(v) => {
+                self.scanner.release(mark);
+                return Some(Ok(v));
+            }
+            Err(err) => err,
+        };
+
+        // the record has started, so a mismatch at the end of file is truncation
+        let err = if is_mismatch(&err) && self.scanner.at_eof() {
+            ScanError { kind: UnexpectedEof, ..err }
+        } else {
+            err
+        };
+        if self.recovery == Stop || !is_mismatch(&err) {
+            self.scanner.release(mark);
+            self.done = true;
+            return Some(Err(err));
+        }
+
+        self.scanner.rewind(mark);
+        let skipped = match self.recovery {
+            Stop => unreachable!(),
+            SkipLine => self.scanner.skip_line(),
+            SkipTo(ref lit) => self.scanner.skip_to(lit.as_slice()),
+        };
+        match skipped {
+            Ok(skipped) => Some(Err(ScanError { skipped: skipped, ..err })),
+            // the next call will most likely fail with the same I/O error
+            Err(_) => Some(Err(err)),
+        }
+    }
 }
 
 mod impls {
     use super::*;
+    use buffer::LookaheadBuffer;
     use std::{char, str};
     use std::from_str::FromStr;
-    use std::io::IoResult;
+    use std::num::FromStrRadix;
+    use std::io::{IoResult, EndOfFile};
+    use std::strbuf::StrBuf;
+
+    pub fn scan_signed_digits<'a, T: FromStr>(s: &'a mut Scanner) -> ScanResult<Option<T>> {
+        scan_decimal_digits(s, true)
+    }
 
-    pub fn scan_signed_digits<'a, T: FromStr>(s: &'a mut Scanner) -> IoResult<Option<T>> {
-        fn scan<'a>(s: &'a mut Scanner, mandatory_sign: bool) -> IoResult<Option<&'a [u8]>> {
+    // the unsigned types do not accept `-` even for zero, which is left unconsumed
+    pub fn scan_unsigned_digits<'a, T: FromStr>(s: &'a mut Scanner) -> ScanResult<Option<T>> {
+        scan_decimal_digits(s, false)
+    }
+
+    fn scan_decimal_digits<'a, T: FromStr>(s: &'a mut Scanner,
+                                           signed: bool) -> ScanResult<Option<T>> {
+        fn scan<'a>(s: &'a mut Scanner, mandatory_sign: bool, signed: bool,
+                    limit: uint) -> IoResult<Option<&'a [u8]>> {
             enum State {
                 ExpectSignOrDigit = 0,   // @ ('+' | '-')?   ('0'..'9')+
                 ExpectSign        = 2,   // @ ('+' | '-')    ('0'..'9')+
@@ -141,14 +903,22 @@ mod impls {
             let mut i = 0;
             let mut state = if mandatory_sign {ExpectSign} else {ExpectSignOrDigit};
             'reading: loop {
-                let buf = try!(s.buf.fill_request(i + 1));
+                let buf = match s.buf.fill_request(i + 1) {
+                    Ok(buf) => buf,
+                    Err(ref err) if err.kind == EndOfFile => { break; }
+                    Err(err) => { return Err(err); }
+                };
                 if buf.len() <= i { break; }
                 for (j, &ch) in buf.slice_from(i).iter().enumerate() {
+                    if i + j >= limit { i += j; break 'reading; }
                     state = match (state, ch as char) {
                         (ExpectSignOrDigit, '+')      => ExpectDigit,
-                        (ExpectSignOrDigit, '-')      => ExpectDigit,
+                        (ExpectSignOrDigit, '-') if signed => ExpectDigit,
                         (ExpectSignOrDigit, '0'..'9') => ExpectMoreDigits,
 
+                        (ExpectSign,        '+')      => ExpectDigit,
+                        (ExpectSign,        '-') if signed => ExpectDigit,
+
                         (ExpectDigit,       '0'..'9') => ExpectMoreDigits,
 
                         (ExpectMoreDigits,  '0'..'9') => ExpectMoreDigits,
@@ -165,102 +935,1267 @@ mod impls {
             Ok(Some(buf.slice_to(i)))
         }
 
-        try!(s.skip_prepad());
+        try_io!(s.skip_prepad());
 
         let mandatory_sign = ((s.flags >> FlagSignPlus as uint) & 1) == 1;
-        let (result, i) = match try!(scan(s, mandatory_sign)) {
-            Some(buf) => (from_str(str::from_utf8(buf).unwrap()), buf.len()),
+        let limit = s.max_chars(); // the digits are in ASCII, so the bytes are the characters
+        let (result, i) = match try_io!(scan(s, mandatory_sign, signed, limit)) {
+            Some(buf) => {
+                let start = if buf[0] == '+' as u8 {1} else {0}; // `from_str` does not accept `+`
+                (from_str(str::from_utf8(buf.slice_from(start)).unwrap()), buf.len())
+            }
             None => { return Ok(None); }
         };
+        // the digits are valid, so it cannot fit
+        let result = match result {
+            Some(result) => result,
+            None => { return Err(ScanError::new(Overflow)); }
+        };
         s.buf.consume(i);
 
-        try!(s.skip_postpad());
-        Ok(result)
+        try_io!(s.skip_postpad());
+        Ok(Some(result))
+    }
+
+    // returns the byte at `offset` bytes ahead of the current position, or `None` at the end
+    // or beyond the width. Only used for ASCII, where the bytes are the characters.
+    fn peek_byte_at(s: &mut Scanner, offset: uint) -> IoResult<Option<u8>> {
+        if offset >= s.max_chars() { return Ok(None); }
+        let buf = match s.buf.fill_request(offset + 1) {
+            Ok(buf) => buf,
+            Err(ref err) if err.kind == EndOfFile => { return Ok(None); }
+            Err(err) => { return Err(err); }
+        };
+        Ok(if buf.len() > offset {Some(buf[offset])} else {None})
+    }
+
+    // advances `offset` over the decimal digits and returns the number of them
+    fn skip_digits(s: &mut Scanner, offset: &mut uint) -> IoResult<uint> {
+        let start = *offset;
+        loop {
+            match try!(peek_byte_at(s, *offset)) {
+                Some(ch) if '0' as u8 <= ch && ch <= '9' as u8 => { *offset += 1; }
+                _ => { return Ok(*offset - start); }
+            }
+        }
+    }
+
+    /// Scans a decimal number with an optional sign, fraction and exponent
+    /// (`-12`, `3.`, `.5`, `1.5e-3` etc.).
+    pub fn scan_float<'a, T: FromStr>(s: &mut Scanner<'a>) -> ScanResult<Option<T>> {
+        try_io!(s.skip_prepad());
+
+        let mut i = 0;
+        let mut positive = false;
+        match try_io!(peek_byte_at(s, 0)) {
+            Some(ch) if ch == '+' as u8 => { positive = true; i += 1; }
+            Some(ch) if ch == '-' as u8 => { i += 1; }
+            _ => {}
+        }
+        let mut ndigits = try_io!(skip_digits(s, &mut i));
+        if try_io!(peek_byte_at(s, i)) == Some('.' as u8) {
+            let mut j = i + 1;
+            let nfrac = try_io!(skip_digits(s, &mut j));
+            if ndigits + nfrac > 0 {
+                ndigits += nfrac;
+                i = j;
+            }
+        }
+        if ndigits == 0 { return Ok(None); }
+        match try_io!(peek_byte_at(s, i)) {
+            Some(ch) if ch == 'e' as u8 || ch == 'E' as u8 => {
+                let mut j = i + 1;
+                match try_io!(peek_byte_at(s, j)) {
+                    Some(ch) if ch == '+' as u8 || ch == '-' as u8 => { j += 1; }
+                    _ => {}
+                }
+                if try_io!(skip_digits(s, &mut j)) > 0 { i = j; }
+            }
+            _ => {}
+        }
+
+        let result = {
+            let buf = try_io!(s.buf.fill_request(i));
+            assert!(buf.len() >= i);
+            let start = if positive {1} else {0}; // `from_str` does not accept `+`
+            from_str(str::from_utf8(buf.slice(start, i)).unwrap())
+        };
+        let result = match result {
+            Some(result) => result,
+            None => { return Err(ScanError::new(InvalidNumber)); }
+        };
+        s.buf.consume(i);
+
+        try_io!(s.skip_postpad());
+        Ok(Some(result))
+    }
+
+    /// Scans the digits in given radix (in any case), with an optional sign if `signed`
+    /// (mandatory with the `+` flag) and then an optional `prefix` (in lower case, but
+    /// matched in any case) if any. The prefix is only skipped when followed by a digit.
+    pub fn scan_radix_digits<'a, T: FromStrRadix>(s: &mut Scanner<'a>, radix: uint,
+                                                  signed: bool,
+                                                  prefix: Option<&str>) -> ScanResult<Option<T>> {
+        fn is_digit(ch: Option<u8>, radix: uint) -> bool {
+            ch.map_or(false, |ch| char::to_digit(ch as char, radix).is_some())
+        }
+
+        try_io!(s.skip_prepad());
+
+        let mut i = 0;
+        let mut negative = false;
+        if signed {
+            let mandatory_sign = ((s.flags >> FlagSignPlus as uint) & 1) == 1;
+            match try_io!(peek_byte_at(s, 0)) {
+                Some(ch) if ch == '+' as u8 => { i += 1; }
+                Some(ch) if ch == '-' as u8 => { negative = true; i += 1; }
+                _ if mandatory_sign => { return Ok(None); }
+                _ => {}
+            }
+        }
+        match prefix {
+            Some(prefix) => {
+                let mut matched = true;
+                for (j, p) in prefix.chars().enumerate() {
+                    match try_io!(peek_byte_at(s, i + j)) {
+                        Some(ch) if char::to_lowercase(ch as char) == p => {}
+                        _ => { matched = false; break; }
+                    }
+                }
+                if matched && is_digit(try_io!(peek_byte_at(s, i + prefix.len())), radix) {
+                    i += prefix.len();
+                }
+            }
+            None => {}
+        }
+
+        let start = i;
+        while is_digit(try_io!(peek_byte_at(s, i)), radix) { i += 1; }
+        if i == start { return Ok(None); }
+
+        let result = {
+            let buf = try_io!(s.buf.fill_request(i));
+            assert!(buf.len() >= i);
+            let digits = str::from_utf8(buf.slice(start, i)).unwrap();
+            if negative {
+                let negated = format!("-{}", digits);
+                FromStrRadix::from_str_radix(negated.as_slice(), radix)
+            } else {
+                FromStrRadix::from_str_radix(digits, radix)
+            }
+        };
+        // the digits are valid, so it cannot fit
+        let result = match result {
+            Some(result) => result,
+            None => { return Err(ScanError::new(Overflow)); }
+        };
+        s.buf.consume(i);
+
+        try_io!(s.skip_postpad());
+        Ok(Some(result))
+    }
+
+    pub fn scan_signed_octal<'a, T: FromStrRadix>(s: &mut Scanner<'a>) -> ScanResult<Option<T>> {
+        scan_radix_digits(s, 8, true, None)
+    }
+
+    pub fn scan_unsigned_octal<'a, T: FromStrRadix>(s: &mut Scanner<'a>)
+                                                   -> ScanResult<Option<T>> {
+        scan_radix_digits(s, 8, false, None)
+    }
+
+    // the `0x` prefix is accepted with the `#` flag
+    fn hex_prefix(s: &Scanner) -> Option<&'static str> {
+        if ((s.flags >> FlagAlternate as uint) & 1) == 1 {Some("0x")} else {None}
+    }
+
+    pub fn scan_signed_hex<'a, T: FromStrRadix>(s: &mut Scanner<'a>) -> ScanResult<Option<T>> {
+        let prefix = hex_prefix(s);
+        scan_radix_digits(s, 16, true, prefix)
+    }
+
+    pub fn scan_unsigned_hex<'a, T: FromStrRadix>(s: &mut Scanner<'a>) -> ScanResult<Option<T>> {
+        let prefix = hex_prefix(s);
+        scan_radix_digits(s, 16, false, prefix)
+    }
+
+    pub fn scan_signed_binary<'a, T: FromStrRadix>(s: &mut Scanner<'a>)
+                                                  -> ScanResult<Option<T>> {
+        scan_radix_digits(s, 2, true, None)
+    }
+
+    pub fn scan_unsigned_binary<'a, T: FromStrRadix>(s: &mut Scanner<'a>)
+                                                    -> ScanResult<Option<T>> {
+        scan_radix_digits(s, 2, false, None)
+    }
+
+    /// Scans any single character, including whitespace.
+    pub fn scan_char<'a>(s: &mut Scanner<'a>) -> ScanResult<Option<char>> {
+        try_io!(s.skip_prepad());
+
+        let (ch, width) = match s.buf.peek_char_at(0) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                // either the end of file or an invalid UTF-8 sequence
+                if try_io!(peek_byte_at(s, 0)).is_some() {
+                    return Err(ScanError::new(InvalidUtf8));
+                }
+                return Ok(None);
+            }
+            Err(ref err) if err.kind == EndOfFile => { return Ok(None); }
+            Err(err) => { return Err(ScanError::from_io(err)); }
+        };
+        s.buf.consume(width);
+
+        try_io!(s.skip_postpad());
+        Ok(Some(ch))
     }
 
     macro_rules! delegate_impls {
         ($($trait_:ident for $ty:ty => $f:expr;)*) => (
             $(
                 impl<'a> $trait_<'a> for $ty {
-                    fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<$ty>> { $f(s) }
+                    fn scan(s: &mut Scanner<'a>) -> ScanResult<Option<$ty>> { $f(s) }
                 }
             )*
         )
     }
 
     delegate_impls! {
+        Read     for int  => scan_signed_digits;
+        Read     for i8   => scan_signed_digits;
+        Read     for i16  => scan_signed_digits;
+        Read     for i32  => scan_signed_digits;
+        Read     for i64  => scan_signed_digits;
+        Read     for uint => scan_unsigned_digits;
+        Read     for u8   => scan_unsigned_digits;
+        Read     for u16  => scan_unsigned_digits;
+        Read     for u32  => scan_unsigned_digits;
+        Read     for u64  => scan_unsigned_digits;
+        Read     for f32  => scan_float;
+        Read     for f64  => scan_float;
+        Read     for char => scan_char;
+        Read     for ~str => scan_string;
+        Read     for &'a str => scan_borrowed_str;
+
+        Integer  for int  => scan_signed_digits;
+        Integer  for i8   => scan_signed_digits;
+        Integer  for i16  => scan_signed_digits;
+        Integer  for i32  => scan_signed_digits;
+        Integer  for i64  => scan_signed_digits;
+        Integer  for uint => scan_unsigned_digits;
+        Integer  for u8   => scan_unsigned_digits;
+        Integer  for u16  => scan_unsigned_digits;
+        Integer  for u32  => scan_unsigned_digits;
+        Integer  for u64  => scan_unsigned_digits;
+
         Signed   for int  => scan_signed_digits;
         Signed   for i8   => scan_signed_digits;
         Signed   for i16  => scan_signed_digits;
         Signed   for i32  => scan_signed_digits;
         Signed   for i64  => scan_signed_digits;
 
-        Unsigned for uint => scan_signed_digits;
-        Unsigned for u8   => scan_signed_digits;
-        Unsigned for u16  => scan_signed_digits;
-        Unsigned for u32  => scan_signed_digits;
-        Unsigned for u64  => scan_signed_digits;
-    }
-
-    impl<'a> String<'a> for ~str {
-        fn scan(s: &'a mut Scanner<'a>) -> IoResult<Option<~str>> {
-            fn drop_incomplete_utf8_suffix(buf: &[u8]) -> (uint, uint) {
-                let mut i = buf.len();
-                while i > 0 {
-                    i -= 1;
-                    let width = str::utf8_char_width(buf[i]);
-                    if width > 1 { // exclude this byte
-                        return (i, i + width);
-                    } else if width == 1 { // include this byte
-                        return (i + 1, i + 2);
-                    }
+        Unsigned for uint => scan_unsigned_digits;
+        Unsigned for u8   => scan_unsigned_digits;
+        Unsigned for u16  => scan_unsigned_digits;
+        Unsigned for u32  => scan_unsigned_digits;
+        Unsigned for u64  => scan_unsigned_digits;
+
+        Char     for char => scan_char;
+
+        Octal    for int  => scan_signed_octal;
+        Octal    for i8   => scan_signed_octal;
+        Octal    for i16  => scan_signed_octal;
+        Octal    for i32  => scan_signed_octal;
+        Octal    for i64  => scan_signed_octal;
+        Octal    for uint => scan_unsigned_octal;
+        Octal    for u8   => scan_unsigned_octal;
+        Octal    for u16  => scan_unsigned_octal;
+        Octal    for u32  => scan_unsigned_octal;
+        Octal    for u64  => scan_unsigned_octal;
+
+        Hex      for int  => scan_signed_hex;
+        Hex      for i8   => scan_signed_hex;
+        Hex      for i16  => scan_signed_hex;
+        Hex      for i32  => scan_signed_hex;
+        Hex      for i64  => scan_signed_hex;
+        Hex      for uint => scan_unsigned_hex;
+        Hex      for u8   => scan_unsigned_hex;
+        Hex      for u16  => scan_unsigned_hex;
+        Hex      for u32  => scan_unsigned_hex;
+        Hex      for u64  => scan_unsigned_hex;
+
+        Binary   for int  => scan_signed_binary;
+        Binary   for i8   => scan_signed_binary;
+        Binary   for i16  => scan_signed_binary;
+        Binary   for i32  => scan_signed_binary;
+        Binary   for i64  => scan_signed_binary;
+        Binary   for uint => scan_unsigned_binary;
+        Binary   for u8   => scan_unsigned_binary;
+        Binary   for u16  => scan_unsigned_binary;
+        Binary   for u32  => scan_unsigned_binary;
+        Binary   for u64  => scan_unsigned_binary;
+
+        String   for ~str => scan_string;
+        String   for &'a str => scan_borrowed_str;
+
+        Float    for f32  => scan_float;
+        Float    for f64  => scan_float;
+        Exp      for f32  => scan_float;
+        Exp      for f64  => scan_float;
+
+        Bytes    for Vec<u8> => scan_bytes;
+    }
+
+    /// Skips the padding and returns the length of the string up to the whitespace
+    /// (or the newline with the `#` flag) or `width` characters, including the trailing
+    /// padding if any. The fixed-width field is read to its end like with `#`.
+    fn scan_str_len<'a>(s: &mut Scanner<'a>) -> ScanResult<Option<uint>> {
+        fn drop_incomplete_utf8_suffix(buf: &[u8]) -> (uint, uint) {
+            let mut i = buf.len();
+            while i > 0 {
+                i -= 1;
+                let width = str::utf8_char_width(buf[i]);
+                if width > 1 { // exclude this byte
+                    return (i, i + width);
+                } else if width == 1 { // include this byte
+                    return (i + 1, i + 2);
                 }
-                (0, 1)
             }
+            (0, 1)
+        }
 
-            try!(s.skip_prepad());
+        try_io!(s.skip_prepad());
 
-            let non_empty = ((s.flags >> FlagSignPlus as uint) & 1) == 1;
-            let end_at_newline = ((s.flags >> FlagAlternate as uint) & 1) == 1;
+        let non_empty = ((s.flags >> FlagSignPlus as uint) & 1) == 1;
+        let fixed = s.fixed_width();
+        let end_at_newline = fixed || ((s.flags >> FlagAlternate as uint) & 1) == 1;
 
-            let mut i = 0;
-            let mut request = 1;
-            'reading: loop {
-                let buf = try!(s.buf.fill_request(request));
-                if buf.len() < request { break; }
-                let (i_, request_) = drop_incomplete_utf8_suffix(buf);
-                assert!(request_ > buf.len());
-                let new = match str::from_utf8(buf.slice(i, i_)) {
-                    Some(buf) => buf,
-                    None => { return Ok(None); } // XXX may raise a premature error
-                };
-                if end_at_newline {
-                    for (j, ch) in new.char_indices() {
-                        if ch == '\r' || ch == '\n' { i += j; break 'reading; }
-                    }
+        let limit = s.max_chars();
+        let mut nchars = 0;
+        let mut i = 0;
+        let mut request = 1;
+        'reading: loop {
+            let buf = match s.buf.fill_request(request) {
+                Ok(buf) => buf,
+                // the field may have nothing but the padding
+                Err(ref err) if fixed && err.kind == EndOfFile => { break; }
+                Err(err) => { return Err(ScanError::from_io(err)); }
+            };
+            if buf.len() < request { break; }
+            let (i_, request_) = drop_incomplete_utf8_suffix(buf);
+            assert!(request_ > buf.len());
+            let new = match str::from_utf8(buf.slice(i, i_)) {
+                Some(buf) => buf,
+                None => { return Err(ScanError::new(InvalidUtf8)); }
+            };
+            for (j, ch) in new.char_indices() {
+                let end = if end_at_newline {
+                    ch == '\r' || ch == '\n'
                 } else {
-                    for (j, ch) in new.char_indices() {
-                        if char::is_whitespace(ch) { i += j; break 'reading; }
+                    char::is_whitespace(ch)
+                };
+                if end || nchars >= limit { i += j; break 'reading; }
+                nchars += 1;
+            }
+            i = i_;
+            request = request_;
+        }
+
+        if non_empty && i == 0 { return Ok(None); }
+        Ok(Some(i))
+    }
+
+    /// Scans a string up to the whitespace (or the newline with the `#` flag) and
+    /// calls `f` with the string without the padding.
+    pub fn scan_str_with<'a, T>(s: &mut Scanner<'a>, f: |&str| -> T) -> ScanResult<Option<T>> {
+        let i = match try!(scan_str_len(s)) {
+            Some(i) => i,
+            None => { return Ok(None); }
+        };
+
+        let (align, fill) = (s.align, s.fill);
+        let ret;
+        if i == 0 {
+            ret = f(""); // the buffer may be at the end of the field
+        } else {
+            let buf = try_io!(s.buf.fill_request(i));
+            assert!(buf.len() >= i);
+            ret = f(super::trim_postpad(align, fill, str::from_utf8(buf.slice_to(i)).unwrap()));
+        }
+        s.buf.consume(i);
+        Ok(Some(ret))
+    }
+
+    /// Same as `scan_str_with` but returns the string borrowed from the scanned string.
+    pub fn scan_borrowed_str<'a>(s: &mut Scanner<'a>) -> ScanResult<Option<&'a str>> {
+        let i = match try!(scan_str_len(s)) {
+            Some(i) => i,
+            None => { return Ok(None); }
+        };
+        let ret = match s.buf.borrow_str(i) {
+            Some(ret) => ret,
+            None => { return Err(ScanError::new(NotBorrowable)); }
+        };
+        s.buf.consume(i);
+        Ok(Some(super::trim_postpad(s.align, s.fill, ret)))
+    }
+
+    pub fn scan_string<'a>(s: &mut Scanner<'a>) -> ScanResult<Option<~str>> {
+        scan_str_with(s, |v| v.to_owned())
+    }
+
+    pub fn scan_strbuf<'a>(s: &mut Scanner<'a>) -> ScanResult<Option<StrBuf>> {
+        scan_str_with(s, |v| StrBuf::from_str(v))
+    }
+
+    /// Same as `scan_string` but returns the raw bytes, which need not be valid UTF-8.
+    /// The string ends at the ASCII whitespace, and the width is counted in bytes.
+    pub fn scan_bytes<'a>(s: &mut Scanner<'a>) -> ScanResult<Option<Vec<u8>>> {
+        try_io!(s.skip_prepad());
+
+        let non_empty = ((s.flags >> FlagSignPlus as uint) & 1) == 1;
+        let fixed = s.fixed_width();
+        let end_at_newline = fixed || ((s.flags >> FlagAlternate as uint) & 1) == 1;
+        match s.buf.fill_request(1) {
+            // the field may have nothing but the padding
+            Err(ref err) if fixed && err.kind == EndOfFile => {}
+            Err(err) => { return Err(ScanError::from_io(err)); }
+            Ok(_) => {}
+        }
+
+        let mut i = 0;
+        loop {
+            let end = match try_io!(peek_byte_at(s, i)) {
+                Some(b) if end_at_newline => b == '\r' as u8 || b == '\n' as u8,
+                Some(b) => super::is_ascii_whitespace(b),
+                None => true,
+            };
+            if end { break; }
+            i += 1;
+        }
+
+        if non_empty && i == 0 { return Ok(None); }
+        if i == 0 { return Ok(Some(Vec::new())); } // the buffer may be at the end of the field
+        let (align, fill) = (s.align, s.fill);
+        let ret = {
+            let buf = try_io!(s.buf.fill_request(i));
+            assert!(buf.len() >= i);
+            Vec::from_slice(super::trim_postpad_bytes(align, fill, buf.slice_to(i)))
+        };
+        s.buf.consume(i);
+        Ok(Some(ret))
+    }
+
+    // skips the padding and returns the length of the input matched by the pattern,
+    // up to `width` characters if any
+    fn capture_len<'a>(s: &mut Scanner<'a>, pat: &Pattern) -> IoResult<Option<uint>> {
+        // `peek_char_at` takes care of characters split across chunks
+        fn peek(buf: &mut LookaheadBuffer, offset: uint) -> IoResult<Option<(char, uint)>> {
+            match buf.peek_char_at(offset) {
+                Err(ref err) if err.kind == EndOfFile => Ok(None),
+                ret => ret,
+            }
+        }
+
+        try!(s.skip_prepad());
+
+        let limit = match s.width {
+            Some(width) if width > 0 => {
+                let mut i = 0;
+                for _ in range(0, width) {
+                    match try!(peek(&mut s.buf, i)) {
+                        Some((_, len)) => { i += len; }
+                        None => { break; }
                     }
                 }
-                i = i_;
-                request = request_;
+                Some(i)
+            }
+            _ => None,
+        };
+        let buf = &mut s.buf;
+        pat.match_prefix(|offset| {
+            if limit.map_or(false, |limit| offset >= limit) {
+                Ok(None)
+            } else {
+                peek(&mut *buf, offset)
             }
+        })
+    }
 
-            if non_empty && i == 0 { return Ok(None); }
+    // skips the trailing padding after the capture
+    fn skip_capture_postpad<'a>(s: &mut Scanner<'a>) -> IoResult<()> {
+        match s.skip_postpad() {
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind == EndOfFile => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
 
-            let ret;
-            {
-                let buf = try!(s.buf.fill_request(i));
-                assert!(buf.len() >= i);
-                ret = str::from_utf8(buf.slice_to(i)).unwrap().to_owned();
-            }
-            s.buf.consume(i);
+    /// Matches the pattern against the input, up to `width` characters if any, and
+    /// calls `f` with the matched string. Nothing is consumed if `f` returns `None`.
+    /// The padding is skipped as usual but not trimmed, since the fill character
+    /// may be a part of the match.
+    pub fn capture_with<'a, T>(s: &mut Scanner<'a>, pat: &Pattern,
+                               f: |&str| -> Option<T>) -> ScanResult<Option<T>> {
+        let i = match try_io!(capture_len(s, pat)) {
+            Some(i) => i,
+            None => { return Ok(None); }
+        };
 
-            // XXX slow
-            let ret = s.trim_postpad(ret).to_owned();
-            Ok(Some(ret))
+        let ret;
+        {
+            let buf = try_io!(s.buf.fill_request(i));
+            assert!(buf.len() >= i);
+            ret = match f(str::from_utf8(buf.slice_to(i)).unwrap()) {
+                Some(v) => v,
+                None => { return Ok(None); }
+            };
         }
+        s.buf.consume(i);
+        try_io!(skip_capture_postpad(s));
+        Ok(Some(ret))
+    }
+
+    /// Same as `capture_with` but returns the string borrowed from the scanned string.
+    pub fn capture_borrowed_str<'a>(s: &mut Scanner<'a>,
+                                    pat: &Pattern) -> ScanResult<Option<&'a str>> {
+        let i = match try_io!(capture_len(s, pat)) {
+            Some(i) => i,
+            None => { return Ok(None); }
+        };
+        let ret = match s.buf.borrow_str(i) {
+            Some(ret) => ret,
+            None => { return Err(ScanError::new(NotBorrowable)); }
+        };
+        s.buf.consume(i);
+        try_io!(skip_capture_postpad(s));
+        Ok(Some(ret))
+    }
+
+    impl<'a> Capture<'a> for ~str {
+        fn capture(s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<Option<~str>> {
+            capture_with(s, pat, |v| Some(v.to_owned()))
+        }
+    }
+
+    impl<'a> Capture<'a> for &'a str {
+        fn capture(s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<Option<&'a str>> {
+            capture_borrowed_str(s, pat)
+        }
+    }
+
+    impl<'a> Capture<'a> for StrBuf {
+        fn capture(s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<Option<StrBuf>> {
+            capture_with(s, pat, |v| Some(StrBuf::from_str(v)))
+        }
+
+        // overwrites the existing buffer instead of allocating a new one
+        fn capture_into(&mut self, s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<bool> {
+            let ret = try!(capture_with(s, pat, |v| {
+                self.truncate(0);
+                self.push_str(v);
+                Some(())
+            }));
+            Ok(ret.is_some())
+        }
+    }
+
+    // the matched string is further parsed into the value
+    macro_rules! from_str_capture_impls {
+        ($($ty:ty;)*) => (
+            $(
+                impl<'a> Capture<'a> for $ty {
+                    fn capture(s: &mut Scanner<'a>, pat: &Pattern) -> ScanResult<Option<$ty>> {
+                        capture_with(s, pat, |v| from_str(v))
+                    }
+                }
+            )*
+        )
+    }
+
+    from_str_capture_impls! {
+        int; i8; i16; i32; i64;
+        uint; u8; u16; u32; u64;
+        f32; f64;
+        char;
+    }
+
+    macro_rules! strbuf_impls {
+        ($($trait_:ident;)*) => (
+            $(
+                impl<'a> $trait_<'a> for StrBuf {
+                    fn scan(s: &mut Scanner<'a>) -> ScanResult<Option<StrBuf>> { scan_strbuf(s) }
+
+                    // overwrites the existing buffer instead of allocating a new one.
+                    // `~str` keeps the default `scan_into`, as it has no capacity beyond
+                    // its length and cannot be refilled without reallocating.
+                    fn scan_into(&mut self, s: &mut Scanner<'a>) -> ScanResult<bool> {
+                        let ret = try!(scan_str_with(s, |v| {
+                            self.truncate(0);
+                            self.push_str(v);
+                        }));
+                        Ok(ret.is_some())
+                    }
+                }
+            )*
+        )
+    }
+
+    strbuf_impls! {
+        Read;
+        String;
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::EndOfFile;
+    use std::strbuf::StrBuf;
+    use buffer::tests::SimulatedBuffer;
+    use regex::Regex;
+
+    #[test]
+    fn test_signed_digits() {
+        let mut s = Scanner::from_str("+5 -5 5");
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(5));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(-5));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(5));
+
+        let mut s = Scanner::from_str("+5");
+        assert_eq!(Scan::for_unsigned::<u8>(&mut s), Ok(5));
+    }
+
+    #[test]
+    fn test_signed_digits_overflow() {
+        let mut s = Scanner::from_str("256");
+        assert_eq!(Scan::for_unsigned::<u8>(&mut s).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("+128");
+        assert_eq!(Scan::for_signed::<i8>(&mut s).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("-128");
+        assert_eq!(Scan::for_signed::<i8>(&mut s), Ok(-128));
+        let mut s = Scanner::from_str("-129");
+        assert_eq!(Scan::for_signed::<i8>(&mut s).unwrap_err().kind, Overflow);
+    }
+
+    #[test]
+    fn test_unsigned_digits() {
+        // the sign is not consumed, and the error is not an overflow
+        let mut s = Scanner::from_str("-5");
+        assert_eq!(Scan::for_unsigned::<u8>(&mut s).unwrap_err().kind, InvalidNumber);
+        assert_eq!(s.expect_str("-5"), Ok(()));
+        let mut s = Scanner::from_str("-0");
+        assert_eq!(Scan::for_integer::<u8>(&mut s).unwrap_err().kind, InvalidNumber);
+        assert_eq!(s.expect_str("-0"), Ok(()));
+        let mut s = Scanner::from_str("-0");
+        assert_eq!(Scan::for_read::<uint>(&mut s).unwrap_err().kind, NoValue);
+        let mut s = Scanner::from_str("-0");
+        assert_eq!(Scan::for_signed::<i8>(&mut s), Ok(0));
+
+        let plus = 1 << FlagSignPlus as uint;
+        let mut s = Scanner::from_str("+5 -5 5");
+        s.set_spec(None, plus, None, AlignUnknown, None);
+        assert_eq!(Scan::for_unsigned::<u8>(&mut s), Ok(5));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_unsigned::<u8>(&mut s).unwrap_err().kind, InvalidNumber);
+        assert_eq!(s.expect_str("-5"), Ok(()));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_unsigned::<u8>(&mut s).unwrap_err().kind, InvalidNumber);
+    }
+
+    #[test]
+    fn test_keep_scanner() {
+        // the group and the alternative look ahead across the chunks, and the second scan
+        // should see what the first scan has looked ahead but not consumed
+        let buf = &[bytes!("1 2"), bytes!("\n3 "), bytes!("4\n")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+
+        let mark = s.mark();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(1));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(2));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(3));
+        assert!(is_mismatch(&s.expect_str(",").unwrap_err()));
+        s.rewind(mark);
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(1));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(2));
+
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(3));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(4));
+        s.skip_whitespace().unwrap();
+        assert!(s.is_eof().unwrap());
+    }
+
+    #[test]
+    fn test_expect_str_ignore_case() {
+        let mut s = Scanner::from_str("WIDTH=1 Width=2");
+        assert_eq!(s.expect_str_ignore_case("width="), Ok(()));
+        assert_eq!(s.expect_str_ignore_case("1 wIDTH"), Ok(()));
+        assert_eq!(s.expect_str("=2"), Ok(()));
+
+        // the non-ASCII letters are folded too, including the final sigma
+        let mut s = Scanner::from_str("\u00c9T\u00c9 \u039f\u0394\u039f\u03a3");
+        assert_eq!(s.expect_str_ignore_case("\u00e9t\u00e9 "), Ok(()));
+        assert_eq!(s.expect_str_ignore_case("\u03bf\u03b4\u03bf\u03c2"), Ok(()));
+        assert!(s.is_eof().unwrap());
+
+        // nothing is consumed on the failure
+        let mut s = Scanner::from_str("Height=1");
+        let err = s.expect_str_ignore_case("HEIGHT:").unwrap_err();
+        assert_eq!(err.kind, LiteralMismatch);
+        assert_eq!(err.offset, 0);
+        assert_eq!(s.expect_str("Height=1"), Ok(()));
+
+        let mut s = Scanner::from_str("WID");
+        assert_eq!(s.expect_str_ignore_case("width").unwrap_err().kind, UnexpectedEof);
+        assert_eq!(s.expect_str("WID"), Ok(()));
+    }
+
+    #[test]
+    fn test_expect_str_ignore_case_chunks() {
+        // "GR\u00dcN=1" split in the middle of `\u00dc` and of the literal
+        let buf = &[&[0x47, 0x52, 0xc3], &[0x9c, 0x4e], &[0x3d, 0x31]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(s.expect_str_ignore_case("gr\u00fcn="), Ok(()));
+        assert_eq!(Scan::for_integer::<int>(&mut s), Ok(1));
+
+        // the mismatch after the chunk boundary is rewound across the chunks
+        let buf = &[&[0x47, 0x52, 0xc3], &[0x9c, 0x4e], &[0x45]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(s.expect_str_ignore_case("gr\u00fcn=").unwrap_err().kind, LiteralMismatch);
+        assert_eq!(s.expect_str("GR\u00dcNE"), Ok(()));
+        assert!(s.is_eof().unwrap());
+    }
+
+    #[test]
+    fn test_capture_class() {
+        let class = CharClass::parse("a-z_").unwrap();
+        let mut s = Scanner::from_str("foo_bar1 x");
+        s.set_spec(Some("v"), 0, None, AlignUnknown, None);
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &class as &Pattern), Ok(~"foo_bar"));
+        assert_eq!(s.assigned_names(), vec!(~"v"));
+        let err = Scan::for_capture::<~str>(&mut s, &class as &Pattern).unwrap_err();
+        assert_eq!(err.kind, NoValue);
+        assert_eq!(err.name, Some(~"v"));
+        assert_eq!(err.offset, 7);
+        assert_eq!(err.assigned, vec!(~"v"));
+
+        // the captured string can be parsed further, and is not consumed if it cannot
+        let digits = CharClass::parse("0-9a-z").unwrap();
+        let mut s = Scanner::from_str("12a 34");
+        assert_eq!(Scan::for_capture::<int>(&mut s, &digits as &Pattern).unwrap_err().kind,
+                   NoValue);
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &digits as &Pattern), Ok(~"12a"));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_capture::<int>(&mut s, &digits as &Pattern), Ok(34));
+        assert!(s.is_eof().unwrap());
+
+        let mut s = Scanner::from_str("");
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &class as &Pattern).unwrap_err().kind,
+                   UnexpectedEof);
+    }
+
+    #[test]
+    fn test_capture_chunks() {
+        // "\uac00\ub098 x" split in the middle of both characters
+        let class = CharClass::parse("\\p{L}").unwrap();
+        let buf = &[&[0xea, 0xb0], &[0x80, 0xeb, 0x82], &[0x98, 0x20, 0x78]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &class as &Pattern),
+                   Ok(~"\uac00\ub098"));
+        assert_eq!(s.expect_str(" x"), Ok(()));
+    }
+
+    #[test]
+    fn test_capture_width() {
+        // the width counts the characters, not the bytes
+        let class = CharClass::parse("^ ").unwrap();
+        let mut s = Scanner::from_str("\uac00\ub098\ub2e4 abc");
+        s.set_spec(None, 0, None, AlignUnknown, Some(2));
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &class as &Pattern),
+                   Ok(~"\uac00\ub098"));
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &class as &Pattern), Ok(~"\ub2e4"));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &class as &Pattern), Ok(~"ab"));
+        assert_eq!(s.expect_str("c"), Ok(()));
+    }
+
+    #[test]
+    fn test_capture_regex() {
+        let re = Regex::new("[0-9]+(\\.[0-9]+)*").unwrap();
+        let buf = &[bytes!("v1."), bytes!("20"), bytes!(".3-beta")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(s.expect_str("v"), Ok(()));
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &re as &Pattern), Ok(~"1.20.3"));
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &re as &Pattern).unwrap_err().kind,
+                   NoValue);
+        assert_eq!(s.expect_str("-beta"), Ok(()));
+
+        // the regular expression backtracks at the width
+        let mut s = Scanner::from_str("1.2.3");
+        s.set_spec(None, 0, None, AlignUnknown, Some(4));
+        assert_eq!(Scan::for_capture::<~str>(&mut s, &re as &Pattern), Ok(~"1.2"));
+        assert_eq!(s.expect_str(".3"), Ok(()));
+    }
+
+    #[test]
+    fn test_into_capture() {
+        let class = CharClass::parse("a-z").unwrap();
+        let mut s = Scanner::from_str("abc 1");
+        s.set_spec(Some("v"), 0, None, AlignUnknown, None);
+        let mut v = ~"previous";
+        assert_eq!(Scan::into_capture(&mut s, &class as &Pattern, &mut v), Ok(()));
+        assert_eq!(v, ~"abc");
+        s.skip_whitespace().unwrap();
+        let err = Scan::into_capture(&mut s, &class as &Pattern, &mut v).unwrap_err();
+        assert_eq!(err.kind, NoValue);
+        assert_eq!(err.assigned, vec!(~"v"));
+        assert_eq!(v, ~"abc");
+    }
+
+    // scans a record like `42;\n`
+    fn int_record(s: &mut Scanner) -> ScanResult<int> {
+        let v = try!(Scan::for_integer::<int>(s));
+        try!(s.expect_str(";\n"));
+        Ok(v)
+    }
+
+    #[test]
+    fn test_skip_line() {
+        let buf = &[bytes!("ab"), bytes!("c\nd")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(s.skip_line().unwrap().as_slice(), bytes!("abc\n"));
+        assert_eq!(s.skip_line().unwrap().as_slice(), bytes!("d"));
+        assert_eq!(s.skip_line().unwrap().as_slice(), bytes!(""));
+    }
+
+    #[test]
+    fn test_skip_to() {
+        // the last byte of the first chunk may start the literal
+        let buf = &[bytes!("ab#"), bytes!("#cd")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(s.skip_to("##").unwrap().as_slice(), bytes!("ab"));
+        assert_eq!(s.expect_str("##cd"), Ok(()));
+
+        let buf = &[bytes!("a#"), bytes!("b#"), bytes!("#")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(s.skip_to("##").unwrap().as_slice(), bytes!("a#b"));
+        assert_eq!(s.expect_str("##"), Ok(()));
+
+        // the literal at the current position is skipped
+        let mut s = Scanner::from_str("##a##b");
+        assert_eq!(s.skip_to("##").unwrap().as_slice(), bytes!("##a"));
+        assert_eq!(s.skip_to("##").unwrap().as_slice(), bytes!("##b"));
+        assert_eq!(s.skip_to("##").unwrap().as_slice(), bytes!(""));
+
+        let mut s = Scanner::from_str("abc");
+        assert_eq!(s.skip_to("##").unwrap().as_slice(), bytes!("abc"));
+
+        // the empty literal skips a single byte
+        let mut s = Scanner::from_str("ab");
+        assert_eq!(s.skip_to("").unwrap().as_slice(), bytes!("a"));
+        assert_eq!(s.skip_to("").unwrap().as_slice(), bytes!("b"));
+        assert_eq!(s.skip_to("").unwrap().as_slice(), bytes!(""));
+    }
+
+    #[test]
+    fn test_records_stop() {
+        let buf = &[bytes!("1;\na"), bytes!("b;"), bytes!("\n3;\n")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut it = Records::new(Scanner::new(&mut b), (), |s, _| int_record(s));
+        assert_eq!(it.next(), Some(Ok(1)));
+        let err = it.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, InvalidNumber);
+        assert!(err.skipped.is_empty());
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_records_skip_line() {
+        let buf = &[bytes!("1;\na"), bytes!("b;"), bytes!("\n3;\n")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut it = Records::new(Scanner::new(&mut b), (), |s, _| int_record(s))
+                         .recover(SkipLine);
+        assert_eq!(it.next(), Some(Ok(1)));
+        let err = it.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, InvalidNumber);
+        assert_eq!(err.skipped.as_slice(), bytes!("ab;\n"));
+        assert_eq!(it.next(), Some(Ok(3)));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_records_rewind() {
+        // the failed record is skipped from its start, not from where it has failed
+        let buf = &[bytes!("12;\n4"), bytes!("x;\n5;\n")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut it = Records::new(Scanner::new(&mut b), (), |s, _| int_record(s))
+                         .recover(SkipLine);
+        assert_eq!(it.next(), Some(Ok(12)));
+        let err = it.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, LiteralMismatch);
+        assert_eq!(err.skipped.as_slice(), bytes!("4x;\n"));
+        assert_eq!(it.next(), Some(Ok(5)));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_records_skip_to() {
+        // scans a record like `<<42>>`
+        fn record(s: &mut Scanner) -> ScanResult<int> {
+            try!(s.expect_str("<<"));
+            let v = try!(Scan::for_integer::<int>(s));
+            try!(s.expect_str(">>"));
+            Ok(v)
+        }
+        let buf = &[bytes!("<<1>><<a"), bytes!("b>><"), bytes!("<2>>")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut it = Records::new(Scanner::new(&mut b), (), |s, _| record(s))
+                         .recover(SkipTo(~"<<"));
+        assert_eq!(it.next(), Some(Ok(1)));
+        let err = it.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, InvalidNumber);
+        assert_eq!(err.skipped.as_slice(), bytes!("<<ab>>"));
+        assert_eq!(it.next(), Some(Ok(2)));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_records_eof() {
+        let mut it = Records::new(Scanner::from_str("1;\n2;\n"), (), |s, _| int_record(s));
+        assert_eq!(it.next(), Some(Ok(1)));
+        assert_eq!(it.next(), Some(Ok(2)));
+        assert_eq!(it.next(), None);
+
+        // the end of file in the middle of a record
+        let buf = &[bytes!("1;\n"), bytes!("2;")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut it = Records::new(Scanner::new(&mut b), (), |s, _| int_record(s));
+        assert_eq!(it.next(), Some(Ok(1)));
+        assert_eq!(it.next().unwrap().unwrap_err().kind, UnexpectedEof);
+        assert_eq!(it.next(), None);
+
+        let mut it = Records::new(Scanner::from_str(""), (), |s, _| int_record(s));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_records_trailing_whitespace() {
+        // scans a record like ` 42`, not consuming the final newline
+        fn record(s: &mut Scanner) -> ScanResult<int> {
+            try!(s.skip_whitespace());
+            Scan::for_integer::<int>(s)
+        }
+        let buf = &[bytes!("1 2"), bytes!("\n"), bytes!(" \n")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut it = Records::new(Scanner::new(&mut b), (), |s, _| record(s));
+        assert_eq!(it.next(), Some(Ok(1)));
+        assert_eq!(it.next(), Some(Ok(2)));
+        assert_eq!(it.next(), None);
+
+        // the whitespace before a record is left to it
+        let mut it = Records::new(Scanner::from_str(" 1;\n"), (), |s, _| {
+            try!(s.expect_str(" "));
+            int_record(s)
+        });
+        assert_eq!(it.next(), Some(Ok(1)));
+        assert_eq!(it.next(), None);
+
+        let mut it = Records::new(Scanner::from_str("\n\n"), (), |s, _| int_record(s));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_hex() {
+        let alternate = 1 << FlagAlternate as uint;
+        let mut s = Scanner::from_str("-0x80 0x7f -0X1 0xg");
+        s.set_spec(None, alternate, None, AlignUnknown, None);
+        assert_eq!(Scan::for_hex::<i8>(&mut s), Ok(-128));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_hex::<i8>(&mut s), Ok(127));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_hex::<int>(&mut s), Ok(-1));
+        s.skip_whitespace().unwrap();
+        // the prefix without digits is not a prefix
+        assert_eq!(Scan::for_hex::<u8>(&mut s), Ok(0));
+        assert_eq!(s.expect_str("xg"), Ok(()));
+
+        // the prefix is not accepted without the `#` flag
+        let mut s = Scanner::from_str("-80 0x1");
+        assert_eq!(Scan::for_hex::<i8>(&mut s), Ok(-128));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_hex::<u8>(&mut s), Ok(0));
+        assert_eq!(s.expect_str("x1"), Ok(()));
+
+        // the unsigned types have no sign
+        let mut s = Scanner::from_str("-1");
+        assert_eq!(Scan::for_hex::<u8>(&mut s).unwrap_err().kind, InvalidNumber);
+    }
+
+    #[test]
+    fn test_hex_overflow() {
+        let mut s = Scanner::from_str("100");
+        assert_eq!(Scan::for_hex::<u8>(&mut s).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("80");
+        assert_eq!(Scan::for_hex::<i8>(&mut s).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("-81");
+        assert_eq!(Scan::for_hex::<i8>(&mut s).unwrap_err().kind, Overflow);
+        let mut s = Scanner::from_str("ff");
+        assert_eq!(Scan::for_hex::<u8>(&mut s), Ok(255));
+    }
+
+    #[test]
+    fn test_hex_sign_plus() {
+        let plus = 1 << FlagSignPlus as uint;
+        let mut s = Scanner::from_str("+7f -7f 7f");
+        s.set_spec(None, plus, None, AlignUnknown, None);
+        assert_eq!(Scan::for_hex::<i8>(&mut s), Ok(127));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_hex::<i8>(&mut s), Ok(-127));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_hex::<i8>(&mut s).unwrap_err().kind, InvalidNumber);
+    }
+
+    #[test]
+    fn test_octal_binary() {
+        let mut s = Scanner::from_str("-17 377 400 -101 11111111");
+        assert_eq!(Scan::for_octal::<i8>(&mut s), Ok(-15));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_octal::<u8>(&mut s), Ok(255));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_octal::<u8>(&mut s).unwrap_err().kind, Overflow);
+        assert_eq!(s.expect_str("400"), Ok(()));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_binary::<int>(&mut s), Ok(-5));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_binary::<i8>(&mut s).unwrap_err().kind, Overflow);
+        assert_eq!(Scan::for_binary::<u8>(&mut s), Ok(255));
+
+        // the unsigned types have no sign
+        let mut s = Scanner::from_str("-1");
+        assert_eq!(Scan::for_octal::<u32>(&mut s).unwrap_err().kind, InvalidNumber);
+        assert_eq!(Scan::for_binary::<uint>(&mut s).unwrap_err().kind, InvalidNumber);
+    }
+
+    #[test]
+    fn test_hex_chunks() {
+        let alternate = 1 << FlagAlternate as uint;
+        let buf = &[bytes!("-0"), bytes!("x8"), bytes!("0 0"), bytes!("x"), bytes!("f"),
+                    bytes!("f")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        s.set_spec(None, alternate, None, AlignUnknown, None);
+        assert_eq!(Scan::for_hex::<i8>(&mut s), Ok(-128));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_hex::<u8>(&mut s), Ok(255));
+        assert!(s.is_eof().unwrap());
+    }
+
+    #[test]
+    fn test_first_line() {
+        assert_eq!(first_line("ab\ncd\n").unwrap(), "ab\n");
+        assert_eq!(first_line("ab\r\ncd").unwrap(), "ab\r\n");
+        assert_eq!(first_line("\n").unwrap(), "\n");
+        assert_eq!(first_line("ab").unwrap(), "ab");
+        assert_eq!(first_line("").unwrap_err().kind, EndOfFile);
+    }
+
+    #[test]
+    fn test_check_line() {
+        fn check(line: &str) -> ScanResult<int> {
+            let mut s = Scanner::from_str(line);
+            s.set_spec(Some("n"), 0, None, AlignUnknown, None);
+            let result = Scan::for_integer::<int>(&mut s);
+            check_line(result, &s, line)
+        }
+        assert_eq!(check("12"), Ok(12));
+        assert_eq!(check("12\n"), Ok(12));
+        assert_eq!(check("12\r\n"), Ok(12));
+        assert_eq!(check("x\n").unwrap_err().kind, InvalidNumber);
+
+        let err = check("12 x\n").unwrap_err();
+        assert_eq!(err.kind, TrailingInput);
+        assert_eq!(err.offset, 2);
+        assert_eq!(err.snippet, ~" x");
+        assert_eq!(err.assigned, vec!(~"n"));
+
+        // only the line ending is allowed, so `\r` alone is a leftover
+        assert_eq!(check("12\r").unwrap_err().snippet, ~"\r");
+        assert_eq!(check("12\n\n").unwrap_err().snippet, ~"\n");
+
+        // the snippet is truncated at the character boundary
+        let line = format!("1a{}\n", "\u00e9".repeat(20));
+        let err = check(line.as_slice()).unwrap_err();
+        assert_eq!(err.kind, TrailingInput);
+        assert_eq!(err.snippet, format!("a{}", "\u00e9".repeat(15)));
+    }
+
+    // a hand-written `Read` scanning like `#[read(fmt = "({x}, {y})")]`
+    #[deriving(Eq,Show)]
+    struct Point { x: int, y: int }
+
+    impl<'a> Read<'a> for Point {
+        fn scan(s: &mut Scanner<'a>) -> ScanResult<Option<Point>> {
+            fn point(s: &mut Scanner) -> ScanResult<Point> {
+                try!(s.expect_str("("));
+                let x = try!(Scan::for_integer::<int>(s));
+                try!(s.expect_str(","));
+                try!(s.skip_whitespace());
+                let y = try!(Scan::for_integer::<int>(s));
+                try!(s.expect_str(")"));
+                Ok(Point { x: x, y: y })
+            }
+
+            let mark = s.mark();
+            match point(s) {
+                Ok(p) => { s.release(mark); Ok(Some(p)) }
+                Err(err) => {
+                    s.rewind(mark);
+                    if is_mismatch(&err) { Ok(None) } else { Err(err) }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_from_str() {
+        assert_eq!(read_from_str::<Point>("(1, -2)"), Some(Point { x: 1, y: -2 }));
+        assert_eq!(read_from_str::<Point>("(1,2)"), Some(Point { x: 1, y: 2 }));
+        assert_eq!(read_from_str::<Point>("(1, 2) "), None);
+        assert_eq!(read_from_str::<Point>("(1, x)"), None);
+        assert_eq!(read_from_str::<Point>(""), None);
+        assert_eq!(read_from_str::<int>("42"), Some(42));
+    }
+
+    #[test]
+    fn test_nested_read() {
+        let mut s = Scanner::from_str("p=(3, 4);");
+        assert_eq!(s.expect_str("p="), Ok(()));
+        s.set_spec(Some("p"), 0, None, AlignUnknown, None);
+        assert_eq!(Scan::for_read::<Point>(&mut s), Ok(Point { x: 3, y: 4 }));
+        assert_eq!(s.expect_str(";"), Ok(()));
+        // the nested scans are not reported as assigned
+        assert_eq!(s.assigned_names(), vec!(~"p"));
+
+        // the nested scan is rewound on the failure
+        let mut s = Scanner::from_str("p=(3, x);");
+        assert_eq!(s.expect_str("p="), Ok(()));
+        s.set_spec(Some("p"), 0, None, AlignUnknown, None);
+        let err = Scan::for_read::<Point>(&mut s).unwrap_err();
+        assert_eq!(err.kind, NoValue);
+        assert_eq!(err.name, Some(~"p"));
+        assert_eq!(err.offset, 2);
+        assert!(err.assigned.is_empty());
+    }
+
+    #[test]
+    fn test_into() {
+        let mut s = Scanner::from_str("42 abc de x");
+        let mut n = 0i;
+        assert_eq!(Scan::into_integer(&mut s, &mut n), Ok(()));
+        assert_eq!(n, 42);
+        s.skip_whitespace().unwrap();
+
+        // `StrBuf` reuses its buffer
+        let mut buf = StrBuf::with_capacity(16);
+        buf.push_str("previous");
+        let capacity = buf.capacity();
+        assert_eq!(Scan::into_string(&mut s, &mut buf), Ok(()));
+        assert_eq!(buf.as_slice(), "abc");
+        assert_eq!(buf.capacity(), capacity);
+        s.skip_whitespace().unwrap();
+
+        let mut owned = ~"previous";
+        assert_eq!(Scan::into_string(&mut s, &mut owned), Ok(()));
+        assert_eq!(owned, ~"de");
+        s.skip_whitespace().unwrap();
+
+        // the place is unchanged without a value
+        let err = Scan::into_integer(&mut s, &mut n).unwrap_err();
+        assert_eq!(err.kind, InvalidNumber);
+        assert_eq!(n, 42);
+        assert_eq!(s.expect_str("x"), Ok(()));
+        assert_eq!(Scan::into_string(&mut s, &mut buf).unwrap_err().kind, UnexpectedEof);
+        assert_eq!(buf.as_slice(), "abc");
+    }
+
+    #[test]
+    fn test_bytes() {
+        // the bytes need not be valid UTF-8, even when split across chunks
+        let buf = &[&[0x61, 0xff, 0x20, 0xc3], &[0xa9, 0xc3], &[0x0a]];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        assert_eq!(Scan::for_bytes::<Vec<u8>>(&mut s), Ok(vec!(0x61, 0xff)));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_bytes::<Vec<u8>>(&mut s), Ok(vec!(0xc3, 0xa9, 0xc3)));
+        s.skip_whitespace().unwrap();
+        assert_eq!(Scan::for_bytes::<Vec<u8>>(&mut s).unwrap_err().kind, UnexpectedEof);
+
+        // the width is counted in bytes, and the fill is trimmed as with the strings
+        let mut s = Scanner::from_str("\u00e9t\u00e9.. x");
+        s.set_spec(None, 0, None, AlignUnknown, Some(3));
+        assert_eq!(Scan::for_bytes::<Vec<u8>>(&mut s), Ok(vec!(0xc3, 0xa9, 0x74)));
+        s.set_spec(None, 0, Some('.'), AlignRight, None);
+        let mut place = vec!(1u8);
+        assert_eq!(Scan::into_bytes(&mut s, &mut place), Ok(()));
+        assert_eq!(place, vec!(0xc3, 0xa9));
+        assert_eq!(s.expect_str(" x"), Ok(()));
+    }
+
+    #[test]
+    fn test_borrowed_str() {
+        let input = "abc de";
+        let mut s = Scanner::from_str(input);
+        let v: &str = Scan::for_string(&mut s).unwrap();
+        assert_eq!(v, "abc");
+        assert_eq!(v.as_ptr(), input.as_ptr());
+        let class = CharClass::parse("^a").unwrap();
+        let v: &str = Scan::for_capture(&mut s, &class as &Pattern).unwrap();
+        assert_eq!(v, " de");
+
+        // `Buffer` cannot lend the input, which is neither a mismatch nor consumed
+        let buf = &[bytes!("abc de")];
+        let mut b = SimulatedBuffer::new(buf);
+        let mut s = Scanner::new(&mut b);
+        s.set_spec(Some("v"), 0, None, AlignUnknown, None);
+        let err = Scan::for_string::<&str>(&mut s).unwrap_err();
+        assert_eq!(err.kind, NotBorrowable);
+        assert_eq!(err.name, Some(~"v"));
+        assert!(!is_mismatch(&err));
+        let err = Scan::for_capture::<&str>(&mut s, &class as &Pattern).unwrap_err();
+        assert_eq!(err.kind, NotBorrowable);
+        assert_eq!(err.offset, 0);
+        assert_eq!(Scan::for_string::<~str>(&mut s), Ok(~"abc"));
+    }
+
+    #[test]
+    fn test_assigned_count() {
+        assert_eq!(assigned_count(Ok(()), 2), Ok(2));
+        assert_eq!(assigned_count(Err(ScanError::new(LiteralMismatch)), 1), Ok(1));
+        assert_eq!(assigned_count(Err(ScanError::new(InvalidNumber)), 0), Ok(0));
+        // the end of file only stops the scanning after an assignment
+        assert_eq!(assigned_count(Err(ScanError::new(UnexpectedEof)), 1), Ok(1));
+        let err = assigned_count(Err(ScanError::new(UnexpectedEof)), 0).unwrap_err();
+        assert_eq!(err.kind, UnexpectedEof);
+        let err = assigned_count(Err(ScanError::new(IoFailure)), 1).unwrap_err();
+        assert_eq!(err.kind, IoFailure);
+        let err = assigned_count(Err(ScanError::new(InvalidFormat)), 1).unwrap_err();
+        assert_eq!(err.kind, InvalidFormat);
+    }
+}
@@ -0,0 +1,45 @@
+// the East Asian Wide (W) and Fullwidth (F) characters from Unicode's EastAsianWidth.txt,
+// coalesced into the major blocks. The ambiguous (A) characters are narrow.
+static WIDE_TABLE: &'static [(char, char)] = &[
+    ('\u1100', '\u115f'), ('\u2329', '\u232a'), ('\u2e80', '\u303e'), ('\u3041', '\u33ff'),
+    ('\u3400', '\u4dbf'), ('\u4e00', '\u9fff'), ('\ua000', '\ua4cf'), ('\ua960', '\ua97f'),
+    ('\uac00', '\ud7a3'), ('\uf900', '\ufaff'), ('\ufe10', '\ufe19'), ('\ufe30', '\ufe6f'),
+    ('\uff00', '\uff60'), ('\uffe0', '\uffe6'),
+    ('\U0001f300', '\U0001f64f'), ('\U0001f900', '\U0001f9ff'),
+    ('\U00020000', '\U0002fffd'), ('\U00030000', '\U0003fffd'),
+];
+
+/// Returns the number of columns taken by the character in the fixed-width text,
+/// which is 2 for the East Asian wide and fullwidth characters and 1 otherwise.
+pub fn char_width(ch: char) -> uint {
+    use std::cmp::{Equal, Less, Greater};
+    let wide = WIDE_TABLE.bsearch(|&(lo, hi)| {
+        if lo <= ch && ch <= hi { Equal }
+        else if hi < ch { Less }
+        else { Greater }
+    }).is_some();
+    if wide {2} else {1}
+}
+
+/// Returns the number of columns taken by the string. See `char_width`.
+pub fn str_width(s: &str) -> uint {
+    s.chars().fold(0, |width, ch| width + char_width(ch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_char_width() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('\u00e9'), 1);
+        assert_eq!(char_width('\u540d'), 2);
+        assert_eq!(char_width('\u3044'), 2);
+        assert_eq!(char_width('\uac00'), 2);
+        assert_eq!(char_width('\uff21'), 2); // fullwidth A
+        assert_eq!(char_width('\uff61'), 1); // halfwidth katakana
+        assert_eq!(char_width('\U00020000'), 2);
+        assert_eq!(str_width("\u540d\u524d: x"), 7);
+    }
+}
@@ -0,0 +1,33 @@
+#[feature(phase)];
+
+#[phase(syntax, link)]
+extern crate read;
+
+use std::io::BufReader;
+
+fn main() {
+    let mut r = BufReader::new(bytes!("1.5 2"));
+
+    let _ = lex!(&mut r, "{x:f}", x: int);
+    //~^ ERROR scan type `f` cannot be used for argument `x` of type `int`
+    //~^^ NOTE declared here
+    let _ = lex!(&mut r, "{x:s}", x: u8);
+    //~^ ERROR scan type `s` cannot be used for argument `x` of type `u8`
+    let _ = lex!(&mut r, "{x:d}", x: uint);
+    //~^ ERROR scan type `d` cannot be used for argument `x` of type `uint`
+    let _ = lex!(&mut r, "{x:o}", x: f64);
+    //~^ ERROR scan type `o` cannot be used for argument `x` of type `f64`
+    let _ = lex!(&mut r, "{x:y}", x: u8);
+    //~^ ERROR scan type `y` cannot be used for argument `x` of type `u8`
+    let _ = lex!(&mut r, "{x}", x: &str);
+    //~^ ERROR argument `x` of type `&str` cannot borrow from the reader
+    //~^^ NOTE use `~str`, or `lex_str!` to borrow from the string
+    let _ = lex!(&mut r, "{xs:*d}", xs: int);
+    //~^ ERROR repeated argument `xs` should have a type `Vec<T>`
+    let _ = lex!(&mut r, "{x:d} {y:d}", x: int);
+    //~^ ERROR no type declared for argument `y`
+    //~^^ NOTE expected `y: <type>` in the argument list
+    let _ = lex!(&mut r, "{x:d}", x: int, z: int);
+    //~^ ERROR argument `z` is never used in the format
+    //~^^ NOTE format is here
+}
@@ -0,0 +1,38 @@
+#[feature(phase)];
+
+#[phase(syntax, link)]
+extern crate read;
+
+#[read(fmt = "{name")] //~ ERROR a premature end of argument
+struct Unclosed { name: ~str }
+
+#[read(fmt = 42)] //~ ERROR format argument must be a string literal.
+struct NotString { name: ~str }
+
+#[read(format = "{name}")] //~ ERROR unrecognized argument to `#[read]`
+struct UnknownArgument { name: ~str }
+
+#[read] //~ ERROR expected `#[read(fmt = "...")]`
+struct NoFormat { name: ~str }
+
+#[read(fmt = "{nam}")] //~ ERROR no field `nam` in `Misspelled`
+struct Misspelled { name: ~str }
+
+#[read(fmt = "{a}")]
+struct Unused { a: int, b: int } //~ ERROR field `b` is never used in the format
+
+#[read(fmt = "{a}[ {b}]")]
+struct NotOption { a: int, b: int }
+//~^ ERROR field `b` in an optional group should have a type `Option<T>`
+
+#[read]
+enum NoVariantFormat {
+    #[read(fmt = "a")]
+    A,
+    B, //~ ERROR no format for variant `B`
+}
+
+#[read(fmt = "a")] //~ ERROR `#[read]` on an enum takes no arguments
+enum EnumFormat { C }
+
+fn main() {}
@@ -0,0 +1,199 @@
+#[feature(phase)];
+
+#[phase(syntax, link)]
+extern crate read;
+
+use std::io::BufReader;
+use read::rt::{Scanner, LiteralMismatch, InvalidNumber, UnexpectedEof};
+
+fn test_named() {
+    let mut r = BufReader::new(bytes!("12 abc -3\n"));
+    let (x, y, z) = lex!(&mut r, "{x} {y} {z:d}", x: int, y: ~str, z: i8).unwrap();
+    assert_eq!(x, 12);
+    assert_eq!(y, ~"abc");
+    assert_eq!(z, -3);
+
+    // a single value is not a tuple
+    let mut r = BufReader::new(bytes!("ff"));
+    let x = lex!(&mut r, "{x:x}", x: u8).unwrap();
+    assert_eq!(x, 255);
+
+    let mut r = BufReader::new(bytes!("x=1, y=2"));
+    let (x, y) = lex!(&mut r, "x={x:u}, y={y:u}", x: uint, y: uint).unwrap();
+    assert_eq!((x, y), (1, 2));
+
+    // the bytes need not be valid UTF-8
+    let mut r = BufReader::new(&[0x61, 0xff, 0x20, 0x31]);
+    let (b, n) = lex!(&mut r, "{b:y} {n:u}", b: Vec<u8>, n: uint).unwrap();
+    assert_eq!((b, n), (vec!(0x61, 0xff), 1));
+}
+
+fn test_mismatch() {
+    let mut r = BufReader::new(bytes!("x=1; y=2"));
+    let err = lex!(&mut r, "x={x:u}, y={y:u}", x: uint, y: uint).unwrap_err();
+    assert_eq!(err.kind, LiteralMismatch);
+    assert_eq!(err.name, None);
+    assert_eq!(err.offset, 3);
+    assert_eq!(err.assigned, vec!(~"x"));
+
+    let mut r = BufReader::new(bytes!("1 x"));
+    let err = lex!(&mut r, "{x:d} {y:d}", x: int, y: int).unwrap_err();
+    assert_eq!(err.kind, InvalidNumber);
+    assert_eq!(err.name, Some(~"y"));
+    assert_eq!(err.offset, 2);
+
+    let mut r = BufReader::new(bytes!("1 "));
+    let err = lex!(&mut r, "{x:d} {y:d}", x: int, y: int).unwrap_err();
+    assert_eq!(err.kind, UnexpectedEof);
+}
+
+fn test_positional() {
+    let mut r = BufReader::new(bytes!("1 ff"));
+    let (a, b) = lex!(&mut r, "{} {1:x}", int, u8).unwrap();
+    assert_eq!((a, b), (1, 255));
+
+    // the positional values come before the named values
+    let mut r = BufReader::new(bytes!("a 2 c"));
+    let (a, b, c) = lex!(&mut r, "{name} {} {1}", int, ~str, name: ~str).unwrap();
+    assert_eq!((a, b, c), (2, ~"c", ~"a"));
+
+    // the types can be inferred without the unnamed types
+    let mut r = BufReader::new(bytes!("3 4"));
+    let (a, b): (int, uint) = lex!(&mut r, "{} {}").unwrap();
+    assert_eq!((a, b), (3, 4));
+}
+
+fn test_suppressed() {
+    let mut r = BufReader::new(bytes!("skipped 42 1.5 x"));
+    let (x, y) = lex!(&mut r, "{*} {x:d} {*:f} {y}", x: int, y: ~str).unwrap();
+    assert_eq!((x, y), (42, ~"x"));
+
+    // the suppressed value is still checked
+    let mut r = BufReader::new(bytes!("x 1"));
+    let err = lex!(&mut r, "{*:d} {y:d}", y: int).unwrap_err();
+    assert_eq!(err.kind, InvalidNumber);
+    assert_eq!(err.name, None);
+}
+
+fn test_group() {
+    let mut r = BufReader::new(bytes!("1 (pid 42);"));
+    let (x, pid) = lex!(&mut r, "{x:d}[ (pid {pid:u})];", x: int, pid: uint).unwrap();
+    assert_eq!((x, pid), (1, Some(42)));
+
+    let mut r = BufReader::new(bytes!("1;"));
+    let (x, pid) = lex!(&mut r, "{x:d}[ (pid {pid:u})];", x: int, pid: uint).unwrap();
+    assert_eq!((x, pid), (1, None));
+
+    // the failed group is rewound to its start
+    let mut r = BufReader::new(bytes!("1 (pid x);"));
+    let err = lex!(&mut r, "{x:d}[ (pid {pid:u})];", x: int, pid: uint).unwrap_err();
+    assert_eq!(err.kind, LiteralMismatch);
+    assert_eq!(err.offset, 1);
+
+    // nested groups
+    let mut r = BufReader::new(bytes!("a=1"));
+    let (a, b) = lex!(&mut r, "a={a:d}[,[ ]b={b:d}]", a: int, b: int).unwrap();
+    assert_eq!((a, b), (1, None));
+    let mut r = BufReader::new(bytes!("a=1, b=2"));
+    let (a, b) = lex!(&mut r, "a={a:d}[,[ ]b={b:d}]", a: int, b: int).unwrap();
+    assert_eq!((a, b), (1, Some(2)));
+}
+
+fn test_repeat() {
+    let mut r = BufReader::new(bytes!("1,2,3;"));
+    let xs = lex!(&mut r, "{xs:*d sep \",\"};", xs: Vec<int>).unwrap();
+    assert_eq!(xs, vec!(1, 2, 3));
+
+    let mut r = BufReader::new(bytes!(";"));
+    let xs = lex!(&mut r, "{xs:*d sep \",\"};", xs: Vec<int>).unwrap();
+    assert_eq!(xs, vec!());
+
+    let mut r = BufReader::new(bytes!("1 2 3"));
+    let err = lex!(&mut r, "{xs:*4..d}", xs: Vec<int>).unwrap_err();
+    assert_eq!(err.name, Some(~"xs"));
+}
+
+fn test_alternatives() {
+    let mut r = BufReader::new(bytes!("12:34"));
+    let (i, h, m, s) = lex!(&mut r, "{h:u}:{m:u}:{s:u}" | "{h:u}:{m:u}",
+                            h: uint, m: uint, s: uint).unwrap();
+    assert_eq!((i, h, m, s), (1, Some(12), Some(34), None));
+
+    let mut r = BufReader::new(bytes!("[main]"));
+    let (i, key, section) = lex!(&mut r, "{key} = {*}" | "\\[{section:[^\\]]}\\]",
+                                 key: ~str, section: ~str).unwrap();
+    assert_eq!((i, key, section), (1, None, Some(~"main")));
+
+    let mut r = BufReader::new(bytes!("x"));
+    let err = lex!(&mut r, "{a:d}" | "{b:f}", a: int, b: f64).unwrap_err();
+    assert_eq!(err.kind, LiteralMismatch);
+    assert_eq!(err.offset, 0);
+}
+
+fn test_into() {
+    let mut a = 0;
+    let mut b = ~"previous";
+    let mut r = BufReader::new(bytes!("1 abc"));
+    assert_eq!(lex!(&mut r, "{a:d} {b}", a => &mut a, b => &mut b), Ok(2));
+    assert_eq!((a, b.as_slice()), (1, "abc"));
+
+    // a mismatch stops the scanning, like C `scanf`
+    let mut r = BufReader::new(bytes!("2 3"));
+    assert_eq!(lex!(&mut r, "{a:d}, {b}", a => &mut a, b => &mut b), Ok(1));
+    assert_eq!((a, b.as_slice()), (2, "abc"));
+
+    let mut r = BufReader::new(bytes!(""));
+    let err = lex!(&mut r, "{a:d} {b}", a => &mut a, b => &mut b).unwrap_err();
+    assert_eq!(err.kind, UnexpectedEof);
+}
+
+fn test_in_scanner() {
+    // the lookahead is kept across the invocations
+    let mut r = BufReader::new(bytes!("1 2 x"));
+    let mut s = Scanner::new(&mut r);
+    assert_eq!(lex!(in &mut s, "{a:d} ", a: int), Ok(1));
+    assert_eq!(lex!(in &mut s, "{a:d} ", a: int), Ok(2));
+    assert_eq!(lex!(in &mut s, "{a:d} ", a: int).unwrap_err().kind, InvalidNumber);
+    assert_eq!(lex!(in &mut s, "{a}", a: ~str), Ok(~"x"));
+}
+
+fn test_ignore_case() {
+    let mut r = BufReader::new(bytes!("WIDTH=1, \u00c9T\u00c9=2"));
+    let (w, e) = lex!(&mut r, "width={w:u}, \u00e9t\u00e9={e:u}" i, w: uint, e: uint).unwrap();
+    assert_eq!((w, e), (1, 2));
+
+    // also the separators of the repetition
+    let mut r = BufReader::new(bytes!("1AND2and3"));
+    let xs = lex!(&mut r, "{xs:*d sep \"and\"}" i, xs: Vec<int>).unwrap();
+    assert_eq!(xs, vec!(1, 2, 3));
+}
+
+fn test_lex_str() {
+    // the consumed length lets the caller continue with the rest
+    let s = "12 abc rest";
+    let ((x, y), n) = lex_str!(s, "{x} {y}", x: int, y: ~str).unwrap();
+    assert_eq!((x, y), (12, ~"abc"));
+    assert_eq!(s.slice_from(n), " rest");
+
+    // the strings can be borrowed from the input
+    let (w, n): (&str, uint) = lex_str!(s, "{w}", w: &str).unwrap();
+    assert_eq!((w, n), ("12", 2));
+
+    let err = lex_str!("1 x", "{x:d} {y:d}", x: int, y: int).unwrap_err();
+    assert_eq!(err.kind, InvalidNumber);
+    assert_eq!(err.offset, 2);
+}
+
+fn main() {
+    test_named();
+    test_mismatch();
+    test_positional();
+    test_suppressed();
+    test_group();
+    test_repeat();
+    test_alternatives();
+    test_into();
+    test_in_scanner();
+    test_ignore_case();
+    test_lex_str();
+}
@@ -0,0 +1,79 @@
+#[feature(phase)];
+
+#[phase(syntax, link)]
+extern crate read;
+
+use std::io::BufReader;
+use read::rt::NoValue;
+
+#[read(fmt = "{name} is {age:u} years old")]
+#[deriving(Eq,Show)]
+struct Person {
+    name: ~str,
+    age: uint,
+}
+
+#[read(fmt = "({:d}, {:d})")]
+#[deriving(Eq,Show)]
+struct Point(int, int);
+
+#[read(fmt = "{name}[ <{email:[^>]}>]")]
+#[deriving(Eq,Show)]
+struct Contact {
+    name: ~str,
+    email: Option<~str>,
+}
+
+#[read]
+#[deriving(Eq,Show)]
+enum Shape {
+    #[read(fmt = "circle {}")]
+    Circle(f64),
+    #[read(fmt = "rect {} {}")]
+    Rect(f64, f64),
+    #[read(fmt = "empty")]
+    Empty,
+}
+
+fn test_struct() {
+    assert_eq!(from_str::<Person>("Bob is 42 years old"),
+               Some(Person { name: ~"Bob", age: 42 }));
+    assert_eq!(from_str::<Person>("Bob is 42 years old!"), None);
+    assert_eq!(from_str::<Person>("Bob is -1 years old"), None);
+
+    assert_eq!(from_str::<Point>("(1, -2)"), Some(Point(1, -2)));
+    assert_eq!(from_str::<Point>("(1,2)"), Some(Point(1, 2)));
+    assert_eq!(from_str::<Point>("(1, x)"), None);
+
+    assert_eq!(from_str::<Contact>("Alice <alice@example.com>"),
+               Some(Contact { name: ~"Alice", email: Some(~"alice@example.com") }));
+    assert_eq!(from_str::<Contact>("Alice"), Some(Contact { name: ~"Alice", email: None }));
+}
+
+fn test_enum() {
+    // the variants are tried in order
+    assert_eq!(from_str::<Shape>("circle 1.5"), Some(Circle(1.5)));
+    assert_eq!(from_str::<Shape>("rect 2 3"), Some(Rect(2.0, 3.0)));
+    assert_eq!(from_str::<Shape>("empty"), Some(Empty));
+    assert_eq!(from_str::<Shape>("square 1"), None);
+    assert_eq!(from_str::<Shape>("circle"), None);
+}
+
+fn test_nested() {
+    // the derived types can be scanned by `lex!` and by other derived types
+    let mut r = BufReader::new(bytes!("p=(3, 4) circle 2"));
+    let (p, s) = lex!(&mut r, "p={p} {s}", p: Point, s: Shape).unwrap();
+    assert_eq!((p, s), (Point(3, 4), Circle(2.0)));
+
+    let mut r = BufReader::new(bytes!("p=(3, x)"));
+    let err = lex!(&mut r, "p={p}", p: Point).unwrap_err();
+    assert_eq!(err.kind, NoValue);
+    assert_eq!(err.name, Some(~"p"));
+    assert_eq!(err.offset, 2);
+}
+
+fn main() {
+    test_struct();
+    test_enum();
+    test_nested();
+}